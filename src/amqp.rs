@@ -0,0 +1,172 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock, OnceLock,
+    },
+    time::Duration,
+};
+
+use amqprs::{
+    channel::Channel,
+    connection::{Connection, OpenConnectionArguments},
+};
+use metrics::counter;
+use tokio::sync::{watch, Mutex, Notify};
+
+use crate::{
+    callbacks::{ConnectionCallbacks, PublishChannelCallbacks},
+    config::env_or,
+    error::Result,
+    events::{enable_confirms, forget_all_confirms, forget_all_declared, forget_confirms},
+};
+
+static SUPERVISOR: OnceLock<ConnectionSupervisor> = OnceLock::new();
+
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How many channels are shared by everything that publishes.
+static PUBLISH_CHANNELS: LazyLock<usize> =
+    LazyLock::new(|| env_or::<usize>("AMQP_PUBLISH_CHANNELS", 8).max(1));
+static PUBLISH_POOL: LazyLock<ChannelPool> = LazyLock::new(|| ChannelPool::new(*PUBLISH_CHANNELS));
+
+/// Keeps the connection to the broker open, replacing it whenever it is lost so that sessions can
+/// open fresh channels instead of being dropped.
+struct ConnectionSupervisor {
+    args: OpenConnectionArguments,
+    connection: watch::Sender<Connection>,
+    closed: Notify,
+}
+
+async fn open(args: &OpenConnectionArguments) -> Result<Connection> {
+    let connection = Connection::open(args).await?;
+    connection.register_callback(ConnectionCallbacks).await?;
+
+    Ok(connection)
+}
+
+fn supervisor() -> &'static ConnectionSupervisor {
+    SUPERVISOR.get().expect("amqp connection not opened")
+}
+
+/// Opens the connection to the broker and starts watching over it.
+pub async fn connect(args: OpenConnectionArguments) -> Result<()> {
+    let connection = open(&args).await?;
+    let _ = SUPERVISOR.set(ConnectionSupervisor {
+        args,
+        connection: watch::Sender::new(connection),
+        closed: Notify::new(),
+    });
+
+    tokio::spawn(supervise());
+
+    // opened up front so that a broken broker setup shows up at startup
+    for _ in 0..*PUBLISH_CHANNELS {
+        publishing_channel().await?;
+    }
+    Ok(())
+}
+
+/// Called by the connection callback once the broker closes the connection.
+pub fn connection_closed() {
+    if let Some(supervisor) = SUPERVISOR.get() {
+        supervisor.closed.notify_waiters();
+    }
+}
+
+async fn supervise() {
+    let supervisor = supervisor();
+
+    loop {
+        let connection = supervisor.connection.borrow().clone();
+        tokio::select! {
+            _ = connection.listen_network_io_failure() => {},
+            _ = supervisor.closed.notified() => {},
+        }
+
+        warn!("amqp connection lost, reconnecting");
+        // network failures don't go through the close callback, and the new connection will hand
+        // out the same channel ids again
+        forget_all_confirms();
+        counter!("harmony_amqp_reconnects_total").increment(1);
+
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        let connection = loop {
+            match open(&supervisor.args).await {
+                Ok(connection) => break connection,
+                Err(e) => {
+                    error!("failed to reconnect to amqp, retrying in {backoff:?}: {e:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
+        };
+
+        info!("reconnected to amqp");
+        // the broker may have restarted and lost every exchange
+        forget_all_declared();
+        supervisor.connection.send_replace(connection);
+    }
+}
+
+/// Opens a channel on the current connection. If the connection is down, this waits for it to be
+/// re-established first.
+pub async fn open_channel() -> Result<Channel> {
+    let mut connection = supervisor().connection.subscribe();
+
+    loop {
+        let current = connection.borrow_and_update().clone();
+        if current.is_open() {
+            let channel = current.open_channel(None).await?;
+            // the id may have belonged to a channel on an earlier connection, whose publishes
+            // must not be resolved by this one's confirms
+            forget_confirms(&channel);
+
+            return Ok(channel);
+        }
+
+        // the sender lives in a static, so this never fails
+        let _ = connection.changed().await;
+    }
+}
+
+/// Long-lived channels shared by every publisher, handed out round-robin. A channel the broker
+/// closed is replaced the next time its turn comes up.
+struct ChannelPool {
+    channels: Vec<Mutex<Option<Channel>>>,
+    next: AtomicUsize,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> Self {
+        Self {
+            channels: (0..size).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    async fn get(&self) -> Result<Channel> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.channels.len();
+        let mut slot = self.channels[index].lock().await;
+
+        if let Some(channel) = &*slot {
+            if channel.is_open() {
+                return Ok(channel.clone());
+            }
+
+            forget_confirms(channel);
+        }
+
+        let channel = open_channel().await?;
+        channel.register_callback(PublishChannelCallbacks).await?;
+        enable_confirms(&channel).await?;
+
+        *slot = Some(channel.clone());
+        Ok(channel)
+    }
+}
+
+/// Returns one of the shared publishing channels. Sessions only consume on their own channels.
+pub async fn publishing_channel() -> Result<Channel> {
+    PUBLISH_POOL.get().await
+}
@@ -0,0 +1,109 @@
+use std::{sync::LazyLock, time::Duration};
+
+use essence::ws::OutboundMessage;
+use tokio::time::Instant;
+
+use crate::config::env_or;
+
+/// How many bytes per second may be sent to a single session, read from
+/// `MAX_SESSION_BYTES_PER_SEC`. Unlimited when 0, which is the default.
+static MAX_BYTES_PER_SEC: LazyLock<u64> = LazyLock::new(|| env_or("MAX_SESSION_BYTES_PER_SEC", 0));
+
+/// Events that can be shed when a session goes over its byte rate, since clients can recover
+/// from missing them.
+pub fn is_low_priority(event: &OutboundMessage) -> bool {
+    matches!(event, OutboundMessage::PresenceUpdate { .. })
+}
+
+/// A token bucket over the bytes sent to a session, holding up to a second's worth of them.
+pub struct ByteBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl ByteBucket {
+    /// Returns `None` if sessions aren't limited.
+    pub fn from_env() -> Option<Self> {
+        (*MAX_BYTES_PER_SEC > 0).then(|| Self::new(*MAX_BYTES_PER_SEC))
+    }
+
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    /// Takes the bytes if they are available right now.
+    pub fn try_take(&mut self, bytes: usize) -> bool {
+        self.refill();
+
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+
+    /// Takes the bytes even if they aren't available, returning how long to wait before sending
+    /// them to stay within the rate.
+    pub fn take(&mut self, bytes: usize) -> Duration {
+        self.refill();
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn low_priority_events_are_shed_until_refilled() {
+        let mut bucket = ByteBucket::new(100);
+
+        assert!(bucket.try_take(60));
+        assert!(bucket.try_take(40));
+        // over budget, so low-priority events are shed
+        assert!(!bucket.try_take(1));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_take(50));
+        assert!(!bucket.try_take(1));
+
+        // refills up to a second's worth, no matter how long it sat idle
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(!bucket.try_take(101));
+        assert!(bucket.try_take(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_events_wait_for_their_bytes() {
+        let mut bucket = ByteBucket::new(100);
+
+        assert_eq!(bucket.take(100), Duration::ZERO);
+        assert_eq!(bucket.take(50), Duration::from_millis(500));
+        // the debt has to be paid off before low-priority events fit again
+        assert!(!bucket.try_take(1));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(bucket.try_take(50));
+    }
+}
@@ -1,33 +1,164 @@
-// use amqprs::{callbacks::ChannelCallback, channel::Channel, Cancel, CloseChannel};
-// use uuid::Uuid;
-
-// use crate::task_manager::TASK_MANAGER;
-
-// type Result<T> = std::result::Result<T, amqprs::error::Error>;
-// pub struct ChannelCallbacks {
-//     session_id: Uuid,
-//     session_id_str: String,
-// }
-
-// #[async_trait::async_trait]
-// impl ChannelCallback for ChannelCallbacks {
-//     fn cancel(&mut self, channel: &Channel, cancel: Cancel) -> Result<()> {
-//         info!(
-//             "channel {}'s consumer id: {} is canceled.",
-//             self.session_id_str,
-//             cancel.consumer_tag()
-//         );
-
-//         Ok(())
-//     }
-
-//     fn close(&mut self, channel: &Channel, _: CloseChannel) -> Result<()> {
-//         info!(
-//             "Channel {} is closing, shutting down client.",
-//             self.session_id_str
-//         );
-//         TASK_MANAGER.shutdown(&self.session_id);
-
-//         Ok(())
-//     }
-// }
+use amqprs::{
+    callbacks::{ChannelCallback, ConnectionCallback},
+    channel::Channel,
+    connection::Connection,
+    Ack, BasicProperties, Cancel, Close, CloseChannel, Nack, Return,
+};
+
+use uuid::Uuid;
+
+use crate::{
+    amqp::connection_closed,
+    events::{confirm, forget_all_confirms, forget_all_declared, forget_confirms},
+    shutdown_notifier::{ShutdownReason, SHUTDOWN_NOTIFIER},
+};
+
+type Result<T> = std::result::Result<T, amqprs::error::Error>;
+
+/// Callbacks registered on the connection to the broker, which is reopened by [`crate::amqp`] once
+/// the broker closes it.
+pub struct ConnectionCallbacks;
+
+#[async_trait::async_trait]
+impl ConnectionCallback for ConnectionCallbacks {
+    async fn close(&mut self, connection: &Connection, close: Close) -> Result<()> {
+        error!("connection {connection} closed by server: {close}");
+        forget_all_confirms();
+        connection_closed();
+
+        Ok(())
+    }
+
+    async fn blocked(&mut self, connection: &Connection, reason: String) {
+        warn!("connection {connection} blocked by server: {reason}");
+    }
+
+    async fn unblocked(&mut self, connection: &Connection) {
+        info!("connection {connection} unblocked by server");
+    }
+}
+
+/// Callbacks registered on the shared publishing channels of [`crate::amqp`]. A closed channel
+/// is replaced by the pool on its next use.
+pub struct PublishChannelCallbacks;
+
+#[async_trait::async_trait]
+impl ChannelCallback for PublishChannelCallbacks {
+    async fn close(&mut self, channel: &Channel, close: CloseChannel) -> Result<()> {
+        error!("publishing channel {channel} closed by server: {close}");
+        forget_confirms(channel);
+        forget_all_declared();
+
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _channel: &Channel, _cancel: Cancel) -> Result<()> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, channel: &Channel, active: bool) -> Result<bool> {
+        info!("flow request from server on channel {channel}, active: {active}");
+
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, channel: &Channel, ack: Ack) {
+        confirm(channel, ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, channel: &Channel, nack: Nack) {
+        warn!(
+            "publish nack on channel {channel}, delivery tag: {}",
+            nack.delivery_tag()
+        );
+        confirm(channel, nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        channel: &Channel,
+        ret: Return,
+        _basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        warn!(
+            "publish returned on channel {channel}: {ret}, {} bytes of content",
+            content.len()
+        );
+    }
+}
+
+/// Callbacks registered on every session channel. The session is shut down once the broker
+/// closes the channel or cancels its consumer.
+pub struct ChannelCallbacks {
+    session_id: Uuid,
+    session_id_str: String,
+}
+
+impl ChannelCallbacks {
+    pub fn new(session_id: Uuid, session_id_str: impl ToString) -> Self {
+        Self {
+            session_id,
+            session_id_str: session_id_str.to_string(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ChannelCallback for ChannelCallbacks {
+    async fn close(&mut self, channel: &Channel, close: CloseChannel) -> Result<()> {
+        error!(
+            "channel {channel} of session {} closed by server: {close}, shutting down session",
+            self.session_id_str
+        );
+        forget_confirms(channel);
+        // the broker closes channels that use exchanges which don't exist, so what we know may be
+        // out of date
+        forget_all_declared();
+        SHUTDOWN_NOTIFIER.shutdown(&self.session_id, ShutdownReason::Terminated);
+
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _channel: &Channel, cancel: Cancel) -> Result<()> {
+        warn!(
+            "consumer {} of session {} canceled by server, shutting down session",
+            cancel.consumer_tag(),
+            self.session_id_str
+        );
+        SHUTDOWN_NOTIFIER.shutdown(&self.session_id, ShutdownReason::Terminated);
+
+        Ok(())
+    }
+
+    async fn flow(&mut self, channel: &Channel, active: bool) -> Result<bool> {
+        info!("flow request from server on channel {channel}, active: {active}");
+
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, channel: &Channel, ack: Ack) {
+        confirm(channel, ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, channel: &Channel, nack: Nack) {
+        warn!(
+            "publish nack on channel {channel}, delivery tag: {}",
+            nack.delivery_tag()
+        );
+        confirm(channel, nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        channel: &Channel,
+        ret: Return,
+        _basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        warn!(
+            "publish returned on channel {channel}: {ret}, {} bytes of content",
+            content.len()
+        );
+    }
+}
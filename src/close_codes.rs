@@ -0,0 +1,174 @@
+use std::borrow::Cow;
+
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+/// Close frame payloads are limited to 125 bytes, two of which are taken by the code.
+const MAX_REASON_LEN: usize = 123;
+
+/// Sent as JSON in the reason of every close frame, so that clients don't have to guess how to
+/// proceed from the code alone.
+#[derive(Serialize)]
+struct CloseAdvice<'a> {
+    reason: &'a str,
+    should_reconnect: bool,
+    /// Sessions can't be resumed yet, so this is always false for now.
+    should_resume: bool,
+    /// How many seconds to wait before reconnecting, if not right away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+/// Close codes sent by the gateway, so that clients can tell whether to reconnect right away or
+/// give up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum GatewayCloseCode {
+    /// The server is shutting down. Reconnect immediately, another instance will take over.
+    ServerRestarting = 4000,
+    /// The token sent in `identify` is invalid. Reconnecting with the same token will fail again.
+    AuthenticationFailed = 4001,
+    /// The client didn't identify in time or stopped answering heartbeats.
+    SessionTimedOut = 4002,
+    /// The requested gateway version is not supported.
+    InvalidApiVersion = 4003,
+    /// A message sent by the client could not be decoded.
+    DecodeError = 4004,
+    /// The client sent a message that isn't valid at this point, or with invalid contents.
+    InvalidPayload = 4005,
+    /// Something went wrong on our side, e.g. the database or redis could not be reached.
+    InternalError = 4006,
+    /// The session was terminated by the server and should not simply reconnect.
+    SessionTerminated = 4007,
+    /// The client is sending messages too quickly.
+    RateLimited = 4008,
+    /// The user already has as many sessions as they are allowed.
+    TooManySessions = 4009,
+}
+
+impl GatewayCloseCode {
+    /// Whether the client may reconnect after being closed with this code. Codes that aren't
+    /// reconnectable will keep failing until the client changes something.
+    pub const fn reconnectable(self) -> bool {
+        !matches!(
+            self,
+            Self::AuthenticationFailed
+                | Self::InvalidApiVersion
+                | Self::SessionTerminated
+                | Self::TooManySessions
+        )
+    }
+
+    /// How many seconds the client should wait before reconnecting.
+    pub const fn retry_after(self) -> Option<u64> {
+        match self {
+            Self::RateLimited | Self::InternalError => Some(5),
+            _ => None,
+        }
+    }
+
+    fn advice(self, reason: &str) -> String {
+        simd_json::to_string(&CloseAdvice {
+            reason,
+            should_reconnect: self.reconnectable(),
+            should_resume: false,
+            retry_after: self.retry_after(),
+        })
+        .expect("simd-json failed to serialize")
+    }
+
+    /// Builds the close frame, with the advice for the client as its reason. The human-readable
+    /// part of the reason is truncated to fit in the frame.
+    pub fn close_frame(self, reason: impl Into<Cow<'static, str>>) -> CloseFrame<'static> {
+        let reason = reason.into();
+        let mut end = reason.len();
+        let mut advice = self.advice(&reason);
+
+        while advice.len() > MAX_REASON_LEN {
+            end = end.saturating_sub(advice.len() - MAX_REASON_LEN);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            advice = self.advice(&reason[..end]);
+        }
+
+        CloseFrame {
+            code: self.into(),
+            reason: advice.into(),
+        }
+    }
+}
+
+impl From<GatewayCloseCode> for CloseCode {
+    fn from(code: GatewayCloseCode) -> Self {
+        Self::from(code as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advice(code: GatewayCloseCode, reason: &'static str) -> String {
+        code.close_frame(reason).reason.into_owned()
+    }
+
+    #[test]
+    fn transient_codes_advise_reconnecting() {
+        for code in [
+            GatewayCloseCode::ServerRestarting,
+            GatewayCloseCode::SessionTimedOut,
+            GatewayCloseCode::InternalError,
+            GatewayCloseCode::RateLimited,
+        ] {
+            assert!(code.reconnectable(), "{code:?}");
+            assert!(advice(code, "transient").contains(r#""should_reconnect":true"#));
+        }
+
+        assert!(advice(GatewayCloseCode::RateLimited, "slow down").contains(r#""retry_after":5"#));
+        assert!(!advice(GatewayCloseCode::ServerRestarting, "restart").contains("retry_after"));
+    }
+
+    #[test]
+    fn auth_and_invalid_codes_advise_against_reconnecting() {
+        for code in [
+            GatewayCloseCode::AuthenticationFailed,
+            GatewayCloseCode::InvalidApiVersion,
+            GatewayCloseCode::SessionTerminated,
+            GatewayCloseCode::TooManySessions,
+        ] {
+            assert!(!code.reconnectable(), "{code:?}");
+            assert!(advice(code, "permanent").contains(r#""should_reconnect":false"#));
+        }
+    }
+
+    #[test]
+    fn short_reasons_are_kept() {
+        let frame = GatewayCloseCode::InvalidPayload.close_frame("bad payload");
+
+        assert_eq!(frame.code, CloseCode::from(4005));
+        assert!(frame.reason.contains(r#""reason":"bad payload""#));
+    }
+
+    #[test]
+    fn long_reasons_are_truncated_to_fit() {
+        let reason = "x".repeat(500);
+        let frame = GatewayCloseCode::InternalError.close_frame(reason);
+
+        assert_eq!(frame.reason.len(), MAX_REASON_LEN);
+        assert!(frame.reason.ends_with(r#""retry_after":5}"#));
+    }
+
+    #[test]
+    fn truncation_respects_char_boundaries() {
+        // two bytes each, so cutting at an arbitrary byte would split one of them
+        for len in 100..140 {
+            let reason = "é".repeat(len);
+            let frame = GatewayCloseCode::DecodeError.close_frame(reason);
+
+            assert!(frame.reason.len() <= MAX_REASON_LEN);
+            assert!(frame.reason.contains(r#""reason":"éé"#));
+            assert!(frame.reason.contains(r#""should_reconnect":true"#));
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+};
+
+use ahash::{HashMap, HashMapExt};
+
+use crate::config::env_or;
+
+pub static CONNECTION_LIMITER: LazyLock<ConnectionLimiter> = LazyLock::new(ConnectionLimiter::new);
+
+/// How many connections a single ip may have open at once.
+static MAX_CONNECTIONS_PER_IP: LazyLock<usize> =
+    LazyLock::new(|| env_or("MAX_CONNECTIONS_PER_IP", 32));
+
+/// Counts the open connections of every ip, so that a single source can't flood the gateway with
+/// connections. Connections are counted towards the peer address, or the address a trusted proxy
+/// reported for it, never one the client sent on its own.
+pub struct ConnectionLimiter {
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Counts towards the connections of an ip until dropped.
+pub struct ConnectionGuard {
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    fn new() -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a connection from the ip, unless it already has as many as it is allowed.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut connections = self
+            .connections
+            .lock()
+            .expect("connection limiter lock poisoned");
+        let count = connections.entry(ip).or_default();
+
+        if *count >= *MAX_CONNECTIONS_PER_IP {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionGuard { ip })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = CONNECTION_LIMITER
+            .connections
+            .lock()
+            .expect("connection limiter lock poisoned");
+
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_limited_until_released() {
+        let ip = IpAddr::from([198, 51, 100, 1]);
+        let mut guards = (0..*MAX_CONNECTIONS_PER_IP)
+            .map(|_| CONNECTION_LIMITER.try_acquire(ip).unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(CONNECTION_LIMITER.try_acquire(ip).is_none());
+        // other ips have their own limit
+        assert!(CONNECTION_LIMITER
+            .try_acquire(IpAddr::from([198, 51, 100, 2]))
+            .is_some());
+
+        guards.pop();
+        guards.push(CONNECTION_LIMITER.try_acquire(ip).unwrap());
+        assert!(CONNECTION_LIMITER.try_acquire(ip).is_none());
+
+        drop(guards);
+        let connections = CONNECTION_LIMITER.connections.lock().unwrap();
+        assert!(!connections.contains_key(&ip));
+    }
+}
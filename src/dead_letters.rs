@@ -0,0 +1,270 @@
+use std::{sync::LazyLock, time::Duration};
+
+use amqprs::{
+    channel::{
+        BasicGetArguments, BasicPublishArguments, Channel, ExchangeDeclareArguments, ExchangeType,
+        QueueBindArguments, QueueDeclareArguments,
+    },
+    types::{FieldName, FieldTable, FieldValue},
+    BasicProperties, Deliver,
+};
+use metrics::gauge;
+
+use crate::{config::env_or, error::Result};
+
+/// Exchange that session queues dead-letter the messages they can't process to.
+pub const DEAD_LETTER_EXCHANGE: &str = "harmony.dlx";
+const DEAD_LETTER_QUEUE: &str = "harmony.dead-letters";
+
+/// How many dead-lettered messages may pile up before it is reported.
+static DEAD_LETTER_ALERT_THRESHOLD: LazyLock<u32> =
+    LazyLock::new(|| env_or("DEAD_LETTER_ALERT_THRESHOLD", 1000));
+
+const SESSION_ID_HEADER: &str = "x-harmony-session-id";
+const USER_ID_HEADER: &str = "x-harmony-user-id";
+const ERROR_HEADER: &str = "x-harmony-error";
+const EXCHANGE_HEADER: &str = "x-harmony-exchange";
+const ROUTING_KEY_HEADER: &str = "x-harmony-routing-key";
+
+/// Declares the dead letter exchange, along with a durable queue that keeps dead-lettered
+/// messages around for operators to inspect.
+pub async fn setup_dead_letter_exchange(channel: &Channel) -> Result<()> {
+    channel
+        .exchange_declare(
+            ExchangeDeclareArguments::of_type(DEAD_LETTER_EXCHANGE, ExchangeType::Fanout)
+                .durable(true)
+                .finish(),
+        )
+        .await?;
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(
+            DEAD_LETTER_QUEUE,
+        ))
+        .await?;
+    channel
+        .queue_bind(QueueBindArguments::new(
+            DEAD_LETTER_QUEUE,
+            DEAD_LETTER_EXCHANGE,
+            "",
+        ))
+        .await?;
+
+    Ok(())
+}
+
+fn field_name(name: &str) -> FieldName {
+    name.try_into().expect("field names are at most 255 bytes")
+}
+
+fn header(properties: &BasicProperties, name: &str) -> Option<String> {
+    match properties.headers()?.get(&field_name(name))? {
+        FieldValue::S(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// The headers recording why and where a message was dead-lettered.
+fn context_headers(
+    session_id: &str,
+    user_id: u64,
+    error: &str,
+    exchange: &str,
+    routing_key: &str,
+) -> FieldTable {
+    let mut headers = FieldTable::new();
+    for (name, value) in [
+        (SESSION_ID_HEADER, session_id.to_string()),
+        (USER_ID_HEADER, user_id.to_string()),
+        (ERROR_HEADER, error.to_string()),
+        (EXCHANGE_HEADER, exchange.to_string()),
+        (ROUTING_KEY_HEADER, routing_key.to_string()),
+    ] {
+        headers.insert(
+            field_name(name),
+            FieldValue::S(
+                value
+                    .as_str()
+                    .try_into()
+                    .expect("header values are short enough"),
+            ),
+        );
+    }
+
+    headers
+}
+
+/// Sends a delivery a session couldn't process to the dead letter exchange, recording which
+/// session failed on it and why. Unlike a nack, this keeps that context around for whoever
+/// inspects the message later.
+pub async fn dead_letter(
+    channel: &Channel,
+    deliver: &Deliver,
+    content: Vec<u8>,
+    session_id: &str,
+    user_id: u64,
+    error: &str,
+) -> Result<()> {
+    let headers = context_headers(
+        session_id,
+        user_id,
+        error,
+        deliver.exchange(),
+        deliver.routing_key(),
+    );
+
+    channel
+        .basic_publish(
+            BasicProperties::default().with_headers(headers).finish(),
+            content,
+            BasicPublishArguments::new(DEAD_LETTER_EXCHANGE, ""),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// A message taken off the dead letter queue.
+#[derive(Debug)]
+pub struct DeadLetter {
+    /// The session that failed to process the message. Unknown for messages the broker
+    /// dead-lettered on its own, e.g. on a nack.
+    pub session_id: Option<String>,
+    pub user_id: Option<u64>,
+    pub error: Option<String>,
+    /// Where the message was originally published to, if known.
+    pub exchange: Option<String>,
+    pub routing_key: Option<String>,
+    pub content: Vec<u8>,
+}
+
+impl DeadLetter {
+    fn from_message(properties: &BasicProperties, content: Vec<u8>) -> Self {
+        Self {
+            session_id: header(properties, SESSION_ID_HEADER),
+            user_id: header(properties, USER_ID_HEADER).and_then(|id| id.parse().ok()),
+            error: header(properties, ERROR_HEADER),
+            exchange: header(properties, EXCHANGE_HEADER),
+            routing_key: header(properties, ROUTING_KEY_HEADER),
+            content,
+        }
+    }
+
+    /// Publishes the message again to where it was originally published to. Returns `false` if
+    /// that isn't known.
+    pub async fn republish(&self, channel: &Channel) -> Result<bool> {
+        let (Some(exchange), Some(routing_key)) = (&self.exchange, &self.routing_key) else {
+            return Ok(false);
+        };
+
+        channel
+            .basic_publish(
+                BasicProperties::default(),
+                self.content.clone(),
+                BasicPublishArguments::new(exchange, routing_key),
+            )
+            .await?;
+
+        Ok(true)
+    }
+}
+
+/// Takes up to `limit` messages off the dead letter queue, for an operator to inspect and
+/// optionally [republish](DeadLetter::republish). Taken messages are removed from the queue.
+pub async fn drain(limit: usize) -> Result<Vec<DeadLetter>> {
+    let channel = crate::amqp::open_channel().await?;
+    let mut drained = Vec::new();
+
+    while drained.len() < limit {
+        let Some((_, properties, content)) = channel
+            .basic_get(
+                BasicGetArguments::new(DEAD_LETTER_QUEUE)
+                    .no_ack(true)
+                    .finish(),
+            )
+            .await?
+        else {
+            break;
+        };
+
+        drained.push(DeadLetter::from_message(&properties, content));
+    }
+    let _ = channel.close().await;
+
+    Ok(drained)
+}
+
+async fn dead_letter_depth(channel: &Channel) -> Result<u32> {
+    let (_, message_count, _) = channel
+        .queue_declare(
+            QueueDeclareArguments::new(DEAD_LETTER_QUEUE)
+                .passive(true)
+                .finish(),
+        )
+        .await?
+        .ok_or("no reply to passive queue_declare")?;
+
+    Ok(message_count)
+}
+
+/// Periodically reports how many messages are waiting in the dead letter queue, warning once
+/// there are more than `DEAD_LETTER_ALERT_THRESHOLD`.
+pub async fn monitor_dead_letters() {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        // a fresh channel every time, which also redeclares everything after a reconnect
+        let result: Result<u32> = async {
+            let channel = crate::amqp::open_channel().await?;
+            setup_dead_letter_exchange(&channel).await?;
+            let depth = dead_letter_depth(&channel).await;
+            let _ = channel.close().await;
+
+            depth
+        }
+        .await;
+
+        match result {
+            Ok(depth) => {
+                gauge!("harmony_dead_letter_queue_depth").set(depth as f64);
+
+                if depth > *DEAD_LETTER_ALERT_THRESHOLD {
+                    warn!(
+                        "{depth} messages in {DEAD_LETTER_QUEUE}, above the alert threshold of {}",
+                        *DEAD_LETTER_ALERT_THRESHOLD
+                    );
+                }
+            }
+            Err(e) => error!("failed to check the dead letter queue: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_letter_context_round_trips() {
+        let headers = context_headers("session", 1234, "bad payload", "5678", "messages");
+        let properties = BasicProperties::default().with_headers(headers).finish();
+        let letter = DeadLetter::from_message(&properties, vec![1, 2, 3]);
+
+        assert_eq!(letter.session_id.as_deref(), Some("session"));
+        assert_eq!(letter.user_id, Some(1234));
+        assert_eq!(letter.error.as_deref(), Some("bad payload"));
+        assert_eq!(letter.exchange.as_deref(), Some("5678"));
+        assert_eq!(letter.routing_key.as_deref(), Some("messages"));
+        assert_eq!(letter.content, [1, 2, 3]);
+    }
+
+    #[test]
+    fn broker_dead_letters_have_no_context() {
+        let letter = DeadLetter::from_message(&BasicProperties::default(), Vec::new());
+
+        assert!(letter.session_id.is_none());
+        assert!(letter.user_id.is_none());
+        assert!(letter.exchange.is_none());
+    }
+}
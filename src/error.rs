@@ -78,13 +78,18 @@ impl_errors! {
     essence::Error,
     essence::db::sqlx::Error,
     rmp_serde::decode::Error,
+    ciborium::de::Error<std::io::Error>,
     simd_json::Error,
     deadpool_redis::PoolError,
+    deadpool_redis::CreatePoolError,
     deadpool_redis::redis::RedisError,
     bincode::error::EncodeError,
     bincode::error::DecodeError,
     amqprs::error::Error,
-    tokio_tungstenite::tungstenite::Error
+    tokio_tungstenite::tungstenite::Error,
+    tokio::time::error::Elapsed,
+    std::io::Error,
+    uuid::Error
 }
 
 impl Display for Error {
@@ -1,25 +1,320 @@
-use std::sync::OnceLock;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
 
-use crate::error::Result;
+use crate::{
+    amqp::publishing_channel,
+    config::{env_or, Intents},
+    error::Result,
+};
+use ahash::{HashMap, HashMapExt, HashSet};
 use amqprs::{
     channel::{
-        BasicPublishArguments, Channel, ExchangeDeclareArguments, ExchangeType, QueueBindArguments,
-        QueueUnbindArguments,
+        BasicPublishArguments, Channel, ConfirmSelectArguments, ExchangeDeclareArguments,
+        ExchangeType, QueueBindArguments, QueueUnbindArguments,
     },
+    types::{FieldTable, FieldValue},
     BasicProperties,
 };
 use bincode::{config::Configuration, Encode};
+use metrics::counter;
+use tokio::sync::oneshot;
 
-// static CHANNEL: OnceLock<Channel> = OnceLock::new();
 pub const CONFIG: Configuration = bincode::config::standard();
 
-// pub fn setup(channel: Channel) {
-//     let _ = CHANNEL.set(channel);
-// }
+/// Encoded events larger than this are dropped instead of being sent to the broker, which would
+/// reject them anyway.
+static MAX_EVENT_SIZE: LazyLock<usize> =
+    LazyLock::new(|| env_or("MAX_EVENT_SIZE", 16 * 1024 * 1024));
+
+/// Whether publishes wait for the broker to confirm them. Turning this off trades detecting lost
+/// publishes for throughput.
+static PUBLISHER_CONFIRMS: LazyLock<bool> = LazyLock::new(|| env_or("PUBLISHER_CONFIRMS", true));
+
+/// How long to wait for the broker to confirm a publish before retrying it.
+static CONFIRM_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("PUBLISH_CONFIRM_TIMEOUT_MS", 5000)));
+
+/// How many times a publish is attempted before giving up, when confirms are enabled.
+static PUBLISH_ATTEMPTS: LazyLock<usize> = LazyLock::new(|| env_or("PUBLISH_ATTEMPTS", 3));
+
+/// Outstanding publishes of every channel in confirm mode, keyed by channel id. Ids are only
+/// unique per connection, so this is cleared whenever the connection is lost.
+static CONFIRMS: LazyLock<Mutex<HashMap<u16, Arc<PendingConfirms>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Exchanges known to exist on the broker, so that publishes don't redeclare them every time.
+/// Auto-deleted exchanges are never cached, since the broker may delete them at any moment.
+static KNOWN_EXCHANGES: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::default()));
+
+#[derive(Default)]
+struct PendingConfirms {
+    /// The delivery tag the broker will assign to the next publish. Held for the duration of a
+    /// publish so that tags are handed out in the same order the broker receives the messages.
+    next_tag: tokio::sync::Mutex<u64>,
+    /// Publishes awaiting an ack (`true`) or nack (`false`), keyed by delivery tag.
+    waiting: Mutex<BTreeMap<u64, oneshot::Sender<bool>>>,
+}
+
+impl PendingConfirms {
+    fn resolve(&self, delivery_tag: u64, multiple: bool, acked: bool) {
+        let mut waiting = self.waiting.lock().expect("confirms lock poisoned");
+
+        if multiple {
+            let rest = waiting.split_off(&(delivery_tag + 1));
+
+            for (_, tx) in std::mem::replace(&mut *waiting, rest) {
+                let _ = tx.send(acked);
+            }
+        } else if let Some(tx) = waiting.remove(&delivery_tag) {
+            let _ = tx.send(acked);
+        }
+    }
+
+    /// Fails every publish waiting on a confirm, which makes them return right away instead of
+    /// running into the confirm timeout.
+    fn fail_all(&self) {
+        // dropping the senders closes the receivers
+        self.waiting.lock().expect("confirms lock poisoned").clear();
+    }
+}
+
+/// Puts the channel into confirm mode. Every publish on it will then wait for the broker to
+/// confirm it, retrying on nacks and timeouts.
+pub async fn enable_confirms(channel: &Channel) -> Result<()> {
+    if !*PUBLISHER_CONFIRMS {
+        return Ok(());
+    }
+
+    channel
+        .confirm_select(ConfirmSelectArguments::default())
+        .await?;
+
+    CONFIRMS.lock().expect("confirms lock poisoned").insert(
+        channel.channel_id(),
+        Arc::new(PendingConfirms {
+            // delivery tags start at 1 once confirm mode is enabled
+            next_tag: tokio::sync::Mutex::new(1),
+            ..Default::default()
+        }),
+    );
+
+    Ok(())
+}
+
+/// Called by the channel callback whenever the broker acks or nacks publishes on the channel.
+pub fn confirm(channel: &Channel, delivery_tag: u64, multiple: bool, acked: bool) {
+    let confirms = CONFIRMS
+        .lock()
+        .expect("confirms lock poisoned")
+        .get(&channel.channel_id())
+        .cloned();
+
+    if let Some(confirms) = confirms {
+        confirms.resolve(delivery_tag, multiple, acked);
+    }
+}
+
+/// Stops tracking confirms for a channel, failing every publish still waiting on one.
+pub fn forget_confirms(channel: &Channel) {
+    let confirms = CONFIRMS
+        .lock()
+        .expect("confirms lock poisoned")
+        .remove(&channel.channel_id());
+
+    if let Some(confirms) = confirms {
+        confirms.fail_all();
+    }
+}
+
+/// Stops tracking confirms for every channel, failing every publish still waiting on one. Used
+/// once the connection is lost, after which channel ids are handed out again.
+pub fn forget_all_confirms() {
+    let confirms = std::mem::take(&mut *CONFIRMS.lock().expect("confirms lock poisoned"));
+
+    for confirms in confirms.into_values() {
+        confirms.fail_all();
+    }
+}
+
+fn known_exchanges() -> std::sync::MutexGuard<'static, HashSet<String>> {
+    KNOWN_EXCHANGES
+        .lock()
+        .expect("known exchanges lock poisoned")
+}
+
+/// Forgets every known exchange, forcing them all to be declared again. Used once the broker
+/// may have lost them, such as after a reconnect.
+pub fn forget_all_declared() {
+    known_exchanges().clear();
+}
+
+/// Encodes the event, dropping it if it is too large for the broker.
+fn encode_payload(exchange: &str, routing_key: &str, data: impl Encode) -> Result<Option<Vec<u8>>> {
+    let payload = bincode::encode_to_vec(data, CONFIG)?;
+
+    if payload.len() > *MAX_EVENT_SIZE {
+        warn!(
+            "dropping event of {} bytes for exchange {exchange} with routing key {routing_key}: exceeds MAX_EVENT_SIZE of {} bytes",
+            payload.len(),
+            *MAX_EVENT_SIZE,
+        );
+
+        return Ok(None);
+    }
+
+    Ok(Some(payload))
+}
+
+async fn declare(
+    channel: &Channel,
+    exchange: &str,
+    kind: ExchangeType,
+    exchange_auto_delete: bool,
+) -> Result<()> {
+    // concurrent publishes to an unknown exchange all declare it, which is harmless since
+    // declares are idempotent
+    if exchange_auto_delete || !known_exchanges().contains(exchange) {
+        channel
+            .exchange_declare(
+                ExchangeDeclareArguments::of_type(exchange, kind)
+                    .auto_delete(exchange_auto_delete)
+                    .finish(),
+            )
+            .await?;
+        debug!("declared exchange {exchange}");
+
+        if !exchange_auto_delete {
+            known_exchanges().insert(exchange.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn confirms_of(channel: &Channel) -> Option<Arc<PendingConfirms>> {
+    CONFIRMS
+        .lock()
+        .expect("confirms lock poisoned")
+        .get(&channel.channel_id())
+        .cloned()
+}
+
+/// Publishes on a channel in confirm mode, returning the delivery tag of the message and a
+/// receiver that resolves once the broker acks or nacks it.
+async fn publish_tracked(
+    channel: &Channel,
+    confirms: &PendingConfirms,
+    args: &BasicPublishArguments,
+    properties: &BasicProperties,
+    payload: Vec<u8>,
+) -> Result<(u64, oneshot::Receiver<bool>)> {
+    counter!("harmony_amqp_publishes_total").increment(1);
+    let mut next_tag = confirms.next_tag.lock().await;
+    let tag = *next_tag;
+
+    // registered before publishing, since the ack may arrive before basic_publish returns
+    let (tx, rx) = oneshot::channel();
+    confirms
+        .waiting
+        .lock()
+        .expect("confirms lock poisoned")
+        .insert(tag, tx);
+
+    if let Err(e) = channel
+        .basic_publish(properties.clone(), payload, args.clone())
+        .await
+    {
+        confirms
+            .waiting
+            .lock()
+            .expect("confirms lock poisoned")
+            .remove(&tag);
 
-// fn get_channel() -> &'static Channel {
-//     CHANNEL.get().expect("channel not set")
-// }
+        return Err(e.into());
+    }
+    *next_tag += 1;
+
+    Ok((tag, rx))
+}
+
+/// Publishes an encoded event, waiting for the broker to confirm it if the channel is in confirm
+/// mode.
+async fn publish_payload(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    properties: &BasicProperties,
+    payload: Vec<u8>,
+) -> Result<()> {
+    let args = BasicPublishArguments::new(exchange, routing_key);
+
+    let Some(confirms) = confirms_of(channel) else {
+        counter!("harmony_amqp_publishes_total").increment(1);
+        channel
+            .basic_publish(properties.clone(), payload, args)
+            .await?;
+        debug!("published message to exchange {exchange} for routing key {routing_key}");
+
+        return Ok(());
+    };
+
+    publish_confirmed(&confirms, exchange, || {
+        publish_tracked(channel, &confirms, &args, properties, payload.clone())
+    })
+    .await?;
+    debug!("published message to exchange {exchange} for routing key {routing_key}");
+
+    Ok(())
+}
+
+/// Publishes with `publish` until the broker acks it, giving up after [`PUBLISH_ATTEMPTS`] nacks
+/// or timeouts. `publish` returns the delivery tag of the message and its confirm.
+async fn publish_confirmed<F, Fut>(
+    confirms: &PendingConfirms,
+    exchange: &str,
+    mut publish: F,
+) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(u64, oneshot::Receiver<bool>)>>,
+{
+    for attempt in 1..=*PUBLISH_ATTEMPTS {
+        let (tag, rx) = publish().await?;
+
+        match tokio::time::timeout(*CONFIRM_TIMEOUT, rx).await {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) => warn!(
+                "broker nacked publish to exchange {exchange} (attempt {attempt}/{})",
+                *PUBLISH_ATTEMPTS,
+            ),
+            Ok(Err(_)) => return Err("channel closed while waiting for publish confirm".into()),
+            Err(_) => {
+                confirms
+                    .waiting
+                    .lock()
+                    .expect("confirms lock poisoned")
+                    .remove(&tag);
+
+                warn!(
+                    "timed out waiting for broker to confirm publish to exchange {exchange} (attempt {attempt}/{})",
+                    *PUBLISH_ATTEMPTS,
+                );
+            }
+        }
+    }
+
+    Err(format!(
+        "publish to exchange {exchange} was not confirmed after {} attempts",
+        *PUBLISH_ATTEMPTS
+    )
+    .as_str()
+    .into())
+}
 
 async fn publish(
     channel: &Channel,
@@ -28,64 +323,192 @@ async fn publish(
     routing_key: impl ToString,
     data: impl Encode,
 ) -> Result<()> {
-    // let channel = get_channel();
+    let (exchange, routing_key) = (exchange.to_string(), routing_key.to_string());
+    let Some(payload) = encode_payload(&exchange, &routing_key, data)? else {
+        return Ok(());
+    };
 
-    channel
-        .exchange_declare(
-            ExchangeDeclareArguments::of_type(&exchange.to_string(), ExchangeType::Topic)
-                .auto_delete(exchange_auto_delete)
-                .finish(),
-        )
-        .await?;
-    debug!("declared exchange {}", exchange.to_string());
+    declare(
+        channel,
+        &exchange,
+        ExchangeType::Topic,
+        exchange_auto_delete,
+    )
+    .await?;
+    publish_payload(
+        channel,
+        &exchange,
+        &routing_key,
+        &BasicProperties::default(),
+        payload,
+    )
+    .await
+}
 
-    channel
-        .basic_publish(
-            BasicProperties::default(),
-            bincode::encode_to_vec(data, CONFIG)?,
-            BasicPublishArguments::new(&exchange.to_string(), &routing_key.to_string()),
-        )
-        .await?;
-    debug!(
-        "published message to exchange {} for routing key {}",
-        exchange.to_string(),
-        routing_key.to_string()
-    );
+/// Publishes the event to every session of a single user.
+pub async fn publish_user_event(channel: &Channel, user_id: u64, event: impl Encode) -> Result<()> {
+    publish(channel, "events", false, user_id.to_string(), event).await?;
 
     Ok(())
 }
 
-pub async fn publish_user_event(channel: &Channel, user_id: u64, event: impl Encode) -> Result<()> {
-    publish(channel, "events", false, user_id.to_string(), event).await?;
+/// Like [`publish_user_event`], through one of the shared publishing channels, for callers that
+/// don't own a channel.
+pub async fn publish_user_event_global(user_id: u64, event: impl Encode) -> Result<()> {
+    publish_user_event(&publishing_channel().await?, user_id, event).await
+}
+
+/// Events meant for the gateway itself rather than its clients. They are published to the
+/// `events` exchange like any other user event, with an empty body and the AMQP `type` property
+/// telling them apart from regular events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InternalEvent {
+    /// The user's token was revoked, e.g. because they logged out or reset their password. Every
+    /// session of the user is closed.
+    TokenRevoked,
+    /// Presence broadcasts of the user were paused by a moderator, who sets the flag read by
+    /// [`crate::presence::is_presence_muted`] before publishing this.
+    PresenceMuted,
+    /// Presence broadcasts of the user were resumed, after the flag was cleared.
+    PresenceUnmuted,
+}
+
+impl InternalEvent {
+    pub fn from_message_type(message_type: &str) -> Option<Self> {
+        match message_type {
+            "harmony.token_revoked" => Some(Self::TokenRevoked),
+            "harmony.presence_muted" => Some(Self::PresenceMuted),
+            "harmony.presence_unmuted" => Some(Self::PresenceUnmuted),
+            _ => None,
+        }
+    }
+}
+
+/// Headers exchange bulk events are published to. Every session queue is bound to it matching any
+/// header named after its user's id, so a publish reaches exactly the users it lists.
+const USERS_EXCHANGE: &str = "harmony.users";
+
+/// How many user ids a single bulk publish lists. The ids are sent as message headers, which have
+/// to fit in one frame of at most 128 KiB by default.
+static BULK_EVENT_CHUNK_SIZE: LazyLock<usize> =
+    LazyLock::new(|| env_or("BULK_EVENT_CHUNK_SIZE", 1000).max(1));
+
+fn field_name(name: &str) -> amqprs::types::FieldName {
+    name.try_into().expect("field names are at most 255 bytes")
+}
+
+/// Binds the session queue to receive bulk events addressed to the user.
+pub async fn bind_user(channel: &Channel, session_id: impl ToString, user_id: u64) -> Result<()> {
+    declare(channel, USERS_EXCHANGE, ExchangeType::Headers, false).await?;
+
+    let mut arguments = FieldTable::new();
+    arguments.insert(
+        field_name("x-match"),
+        FieldValue::S("any".try_into().expect("short string")),
+    );
+    arguments.insert(field_name(&user_id.to_string()), FieldValue::t(true));
+
+    channel
+        .queue_bind(QueueBindArguments {
+            queue: session_id.to_string(),
+            exchange: USERS_EXCHANGE.to_string(),
+            routing_key: String::new(),
+            arguments,
+            ..Default::default()
+        })
+        .await?;
 
     Ok(())
 }
 
+/// Publishes the event to many users at once through the users exchange. Each publish lists up to
+/// [`BULK_EVENT_CHUNK_SIZE`] recipients in its headers, and the broker delivers it to the session
+/// queues of exactly those users.
 pub async fn publish_bulk_event(
     channel: &Channel,
     user_ids: impl AsRef<[u64]>,
     event: impl Encode,
 ) -> Result<()> {
-    let routing_key = user_ids
-        .as_ref()
-        .into_iter()
-        .map(ToString::to_string)
-        .collect::<Vec<_>>()
-        .join(".");
+    let user_ids = user_ids.as_ref();
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+
+    let Some(payload) = encode_payload(USERS_EXCHANGE, "", event)? else {
+        return Ok(());
+    };
+    declare(channel, USERS_EXCHANGE, ExchangeType::Headers, false).await?;
+
+    let chunks = user_ids
+        .chunks(*BULK_EVENT_CHUNK_SIZE)
+        .map(|chunk| {
+            let mut headers = FieldTable::new();
+            for user_id in chunk {
+                headers.insert(field_name(&user_id.to_string()), FieldValue::t(true));
+            }
+
+            BasicProperties::default().with_headers(headers).finish()
+        })
+        .collect::<Vec<_>>();
+
+    let Some(confirms) = confirms_of(channel) else {
+        for properties in &chunks {
+            publish_payload(channel, USERS_EXCHANGE, "", properties, payload.clone()).await?;
+        }
+
+        return Ok(());
+    };
+
+    // every chunk is published before any confirm is awaited, so that a large fan-out waits for
+    // a single confirm window instead of one per chunk
+    let args = BasicPublishArguments::new(USERS_EXCHANGE, "");
+    let mut pending = Vec::with_capacity(chunks.len());
+    for properties in &chunks {
+        pending.push((
+            properties,
+            publish_tracked(channel, &confirms, &args, properties, payload.clone()).await?,
+        ));
+    }
+
+    let deadline = tokio::time::Instant::now() + *CONFIRM_TIMEOUT;
+    for (properties, (tag, rx)) in pending {
+        match tokio::time::timeout_at(deadline, rx).await {
+            Ok(Ok(true)) => continue,
+            Ok(Ok(false)) => warn!("broker nacked bulk publish {tag}, retrying"),
+            Ok(Err(_)) => return Err("channel closed while waiting for publish confirm".into()),
+            Err(_) => {
+                confirms
+                    .waiting
+                    .lock()
+                    .expect("confirms lock poisoned")
+                    .remove(&tag);
 
-    publish(channel, "events", false, routing_key, event).await?;
+                warn!("timed out waiting for broker to confirm bulk publish {tag}, retrying");
+            }
+        }
+
+        // chunks that didn't make it are retried one at a time
+        publish_payload(channel, USERS_EXCHANGE, "", properties, payload.clone()).await?;
+    }
 
     Ok(())
 }
 
-pub async fn _publish_guild_event(
-    channel: &Channel,
-    guild_id: u64,
+/// Like [`publish_bulk_event`], through one of the shared publishing channels, for callers that
+/// don't own a channel.
+pub async fn publish_bulk_event_global(
+    user_ids: impl AsRef<[u64]>,
     event: impl Encode,
 ) -> Result<()> {
-    publish(channel, guild_id.to_string(), true, "all", event).await?; // routing_key all will be replaced with intent.
+    publish_bulk_event(&publishing_channel().await?, user_ids, event).await
+}
 
-    Ok(())
+/// The routing keys a session with the given intents binds to guild and DM exchanges with.
+/// Events published with `all` are still received regardless of intents.
+fn binding_keys(intents: Intents) -> impl Iterator<Item = String> {
+    intents
+        .routing_keys()
+        .chain(std::iter::once("all".to_string()))
 }
 
 pub async fn subscribe(
@@ -93,7 +516,10 @@ pub async fn subscribe(
     exchange: impl ToString,
     session_id: impl ToString,
     kind: impl ToString,
+    intents: Intents,
 ) -> Result<()> {
+    // always declared, since the auto-deleted exchange may be gone by now even if it existed
+    // a moment ago
     channel
         .exchange_declare(ExchangeDeclareArguments {
             exchange: exchange.to_string(),
@@ -103,14 +529,16 @@ pub async fn subscribe(
         })
         .await?;
 
-    channel
-        .queue_bind(QueueBindArguments {
-            queue: session_id.to_string(),
-            exchange: exchange.to_string(),
-            routing_key: "all".to_string(), // to be replaced by intents
-            ..Default::default()
-        })
-        .await?;
+    for routing_key in binding_keys(intents) {
+        channel
+            .queue_bind(QueueBindArguments {
+                queue: session_id.to_string(),
+                exchange: exchange.to_string(),
+                routing_key,
+                ..Default::default()
+            })
+            .await?;
+    }
 
     Ok(())
 }
@@ -119,15 +547,113 @@ pub async fn unsubscribe(
     channel: &Channel,
     exchange: impl ToString,
     session_id: impl ToString,
+    intents: Intents,
 ) -> Result<()> {
-    channel
-        .queue_unbind(QueueUnbindArguments {
-            queue: session_id.to_string(),
-            exchange: exchange.to_string(),
-            routing_key: "all".to_string(),
-            ..Default::default()
-        })
-        .await?;
+    for routing_key in binding_keys(intents) {
+        channel
+            .queue_unbind(QueueUnbindArguments {
+                queue: session_id.to_string(),
+                exchange: exchange.to_string(),
+                routing_key,
+                ..Default::default()
+            })
+            .await?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    /// Stands in for [`publish_tracked`], confirming every publish right away with whatever
+    /// `acked` says for its delivery tag.
+    fn publish_fake<'a>(
+        confirms: &'a PendingConfirms,
+        published: &'a AtomicU64,
+        acked: impl Fn(u64) -> bool + 'a,
+    ) -> impl FnMut() -> std::future::Ready<Result<(u64, oneshot::Receiver<bool>)>> + 'a {
+        move || {
+            let tag = published.fetch_add(1, Ordering::Relaxed) + 1;
+            let (tx, rx) = oneshot::channel();
+            confirms.waiting.lock().unwrap().insert(tag, tx);
+            confirms.resolve(tag, false, acked(tag));
+
+            std::future::ready(Ok((tag, rx)))
+        }
+    }
+
+    #[test]
+    fn resolve_multiple_confirms_up_to_tag() {
+        let confirms = PendingConfirms::default();
+        let mut receivers = (1..=3)
+            .map(|tag| {
+                let (tx, rx) = oneshot::channel();
+                confirms.waiting.lock().unwrap().insert(tag, tx);
+                rx
+            })
+            .collect::<Vec<_>>();
+
+        confirms.resolve(2, true, true);
+        assert_eq!(receivers[0].try_recv(), Ok(true));
+        assert_eq!(receivers[1].try_recv(), Ok(true));
+        assert!(receivers[2].try_recv().is_err());
+
+        confirms.resolve(3, false, false);
+        assert_eq!(receivers[2].try_recv(), Ok(false));
+        assert!(confirms.waiting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn nacked_publish_is_retried() {
+        let confirms = PendingConfirms::default();
+        let published = AtomicU64::new(0);
+
+        let result = publish_confirmed(
+            &confirms,
+            "test",
+            publish_fake(&confirms, &published, |tag| tag > 1),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(published.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn publish_that_keeps_failing_returns_an_error() {
+        let confirms = PendingConfirms::default();
+        let published = AtomicU64::new(0);
+
+        let result = publish_confirmed(
+            &confirms,
+            "test",
+            publish_fake(&confirms, &published, |_| false),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(published.load(Ordering::Relaxed), *PUBLISH_ATTEMPTS as u64);
+    }
+
+    #[tokio::test]
+    async fn forgotten_confirms_fail_waiting_publishes() {
+        let confirms = PendingConfirms::default();
+        let (tx, rx) = oneshot::channel();
+        confirms.waiting.lock().unwrap().insert(1, tx);
+
+        confirms.fail_all();
+        // resolves right away instead of waiting for the confirm timeout
+        assert!(rx.await.is_err());
+    }
+
+    #[test]
+    fn oversize_payloads_are_dropped() {
+        let fits = encode_payload("events", "1234", vec![0_u8; 1024]).unwrap();
+        assert!(fits.is_some_and(|payload| payload.len() <= *MAX_EVENT_SIZE));
+
+        let oversize = encode_payload("events", "1234", vec![0_u8; *MAX_EVENT_SIZE]).unwrap();
+        assert!(oversize.is_none());
+    }
+}
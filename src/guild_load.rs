@@ -0,0 +1,174 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
+use metrics::gauge;
+
+use crate::config::env_or;
+
+/// Only one in this many guild events delivered to sessions is counted.
+static SAMPLE_RATE: LazyLock<u64> =
+    LazyLock::new(|| env_or::<u64>("GUILD_LOAD_SAMPLE_RATE", 16).max(1));
+/// How many of the busiest guilds are reported.
+static TOP_N: LazyLock<usize> = LazyLock::new(|| env_or::<usize>("GUILD_LOAD_TOP_N", 20).max(1));
+
+/// How often the busiest guilds are reported, after which every count is halved so that guilds
+/// that calmed down fall out of the top.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+static SAMPLED: AtomicU64 = AtomicU64::new(0);
+static LOAD: LazyLock<Mutex<GuildLoad>> = LazyLock::new(|| Mutex::new(GuildLoad::new(*TOP_N)));
+
+/// Approximate event counts of the busiest guilds. Only a bounded number of guilds are tracked;
+/// once full, a new guild replaces the least busy one and takes over its count, so that busy
+/// guilds still make it in even if they show up late.
+struct GuildLoad {
+    top_n: usize,
+    counts: HashMap<u64, f64>,
+    /// Guilds that are currently reported, so that their gauges can be zeroed once they drop out.
+    reported: HashSet<u64>,
+}
+
+impl GuildLoad {
+    fn new(top_n: usize) -> Self {
+        Self {
+            top_n,
+            counts: HashMap::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.top_n * 10
+    }
+
+    fn add(&mut self, guild_id: u64, weight: f64) {
+        if let Some(count) = self.counts.get_mut(&guild_id) {
+            *count += weight;
+            return;
+        }
+
+        let mut base = 0.0;
+        if self.counts.len() >= self.capacity() {
+            let Some((&least, &count)) = self.counts.iter().min_by(|(_, a), (_, b)| a.total_cmp(b))
+            else {
+                return;
+            };
+
+            self.counts.remove(&least);
+            base = count;
+        }
+        self.counts.insert(guild_id, base + weight);
+    }
+
+    /// Ranks the busiest guilds, busiest first, and then halves every count. Also returns the
+    /// guilds that were reported last time but no longer are.
+    fn rotate(&mut self) -> (Vec<(u64, f64)>, Vec<u64>) {
+        let mut top = self
+            .counts
+            .iter()
+            .map(|(&guild_id, &count)| (guild_id, count))
+            .collect::<Vec<_>>();
+        top.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+        top.truncate(self.top_n);
+
+        let reported = top
+            .iter()
+            .map(|&(guild_id, _)| guild_id)
+            .collect::<HashSet<_>>();
+        let dropped = self.reported.difference(&reported).copied().collect();
+        self.reported = reported;
+
+        self.counts.retain(|_, count| {
+            *count /= 2.0;
+            *count >= 1.0
+        });
+
+        (top, dropped)
+    }
+}
+
+/// Counts an event of the guild that was delivered to a session, if it is sampled.
+pub fn record(guild_id: u64) {
+    if SAMPLED.fetch_add(1, Ordering::Relaxed) % *SAMPLE_RATE != 0 {
+        return;
+    }
+
+    LOAD.lock()
+        .expect("guild load lock poisoned")
+        .add(guild_id, *SAMPLE_RATE as f64);
+}
+
+/// Periodically reports the approximate event counts of the busiest guilds, labelled by guild id.
+pub async fn report() {
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let (top, dropped) = LOAD.lock().expect("guild load lock poisoned").rotate();
+
+        for guild_id in dropped {
+            gauge!("harmony_guild_events", "guild_id" => guild_id.to_string()).set(0.0);
+        }
+        for (guild_id, count) in top {
+            gauge!("harmony_guild_events", "guild_id" => guild_id.to_string()).set(count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_guilds_evict_the_least_busy_one() {
+        let mut load = GuildLoad::new(1);
+        for guild_id in 0..10 {
+            load.add(guild_id, (guild_id + 1) as f64);
+        }
+
+        load.add(100, 1.0);
+        assert_eq!(load.counts.len(), 10);
+        assert!(!load.counts.contains_key(&0));
+        // takes over the count of the guild it replaced
+        assert_eq!(load.counts[&100], 2.0);
+    }
+
+    #[test]
+    fn busiest_guilds_are_reported_first() {
+        let mut load = GuildLoad::new(2);
+        load.add(1, 5.0);
+        load.add(2, 50.0);
+        load.add(3, 20.0);
+
+        let (top, dropped) = load.rotate();
+        assert_eq!(top, [(2, 50.0), (3, 20.0)]);
+        assert!(dropped.is_empty());
+
+        load.add(1, 100.0);
+        let (top, dropped) = load.rotate();
+        assert_eq!(top, [(1, 102.5), (2, 25.0)]);
+        assert_eq!(dropped, [3]);
+    }
+
+    #[test]
+    fn counts_are_halved_until_forgotten() {
+        let mut load = GuildLoad::new(5);
+        load.add(1, 4.0);
+        load.add(2, 1.5);
+
+        load.rotate();
+        assert_eq!(load.counts[&1], 2.0);
+        assert!(!load.counts.contains_key(&2));
+
+        load.rotate();
+        assert_eq!(load.counts[&1], 1.0);
+        load.rotate();
+        assert!(load.counts.is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use ahash::{HashMap, HashMapExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::env_or;
+
+pub static IDENTIFY_LIMITER: LazyLock<IdentifyLimiter> = LazyLock::new(IdentifyLimiter::new);
+
+/// How many identifies from a single ip may be processed at once.
+static MAX_CONCURRENT_IDENTIFIES: LazyLock<usize> =
+    LazyLock::new(|| env_or("MAX_CONCURRENT_IDENTIFIES_PER_IP", 4));
+/// How long an identify waits for one of the ip's slots before it is rejected.
+static IDENTIFY_QUEUE_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("IDENTIFY_QUEUE_TIMEOUT_SECS", 10)));
+
+/// Limits how many identifies, i.e. token lookups and Ready builds, a single ip can have in flight,
+/// so that a reconnect storm from one source can't monopolize the database. Keyed on the same
+/// verified client address as the connection limiter.
+pub struct IdentifyLimiter {
+    semaphores: Mutex<HashMap<IpAddr, Arc<Semaphore>>>,
+}
+
+/// Holds one of an ip's identify slots until dropped.
+pub struct IdentifyPermit {
+    ip: IpAddr,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl IdentifyLimiter {
+    fn new() -> Self {
+        Self {
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for a free identify slot for the ip. Returns `None` if none frees up in time.
+    pub async fn acquire(&self, ip: IpAddr) -> Option<IdentifyPermit> {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .expect("identify limiter lock poisoned")
+            .entry(ip)
+            .or_insert_with(|| Arc::new(Semaphore::new(*MAX_CONCURRENT_IDENTIFIES)))
+            .clone();
+
+        let permit = tokio::time::timeout(*IDENTIFY_QUEUE_TIMEOUT, semaphore.acquire_owned())
+            .await
+            .ok()
+            .and_then(Result::ok);
+
+        // the guard cleans up the semaphore even if the slot wasn't acquired
+        let permit = IdentifyPermit { ip, permit };
+        permit.permit.is_some().then_some(permit)
+    }
+}
+
+impl Drop for IdentifyPermit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+
+        let mut semaphores = IDENTIFY_LIMITER
+            .semaphores
+            .lock()
+            .expect("identify limiter lock poisoned");
+
+        // nobody else is holding or waiting for a slot of this ip anymore
+        if semaphores
+            .get(&self.ip)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            semaphores.remove(&self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn identifies_wait_for_a_free_slot() {
+        let ip = IpAddr::from([203, 0, 113, 1]);
+        let mut permits = Vec::new();
+        for _ in 0..*MAX_CONCURRENT_IDENTIFIES {
+            permits.push(IDENTIFY_LIMITER.acquire(ip).await.unwrap());
+        }
+
+        // every slot is taken, so this one times out
+        assert!(IDENTIFY_LIMITER.acquire(ip).await.is_none());
+        // other ips have slots of their own
+        assert!(IDENTIFY_LIMITER
+            .acquire(IpAddr::from([203, 0, 113, 2]))
+            .await
+            .is_some());
+
+        permits.pop();
+        permits.push(IDENTIFY_LIMITER.acquire(ip).await.unwrap());
+
+        drop(permits);
+        let semaphores = IDENTIFY_LIMITER.semaphores.lock().unwrap();
+        assert!(!semaphores.contains_key(&ip));
+    }
+}
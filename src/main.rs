@@ -3,23 +3,115 @@
 #[macro_use]
 extern crate log;
 
+mod amqp;
+mod bandwidth;
 mod callbacks;
+mod close_codes;
 mod config;
+mod connection_limiter;
+mod dead_letters;
 mod error;
 mod events;
+mod guild_load;
+mod identify_limiter;
+mod prefetch;
 mod presence;
+#[cfg(feature = "proxy-protocol")]
+mod proxy_protocol;
+mod send_queue;
+mod shutdown_notifier;
 mod socket_accept;
+mod task_manager;
+mod tls;
 mod websocket;
 
-use std::time::Duration;
+use std::{net::SocketAddr, sync::LazyLock, time::Duration};
 
-use amqprs::{
-    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
-    connection::{Connection, OpenConnectionArguments},
+use config::env_or;
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use shutdown_notifier::{ShutdownReason, SHUTDOWN_NOTIFIER};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    task::JoinSet,
 };
-use tokio::{net::TcpListener, runtime::Runtime};
+use tokio_rustls::TlsAcceptor;
+
+/// How long sessions are given to close on their own once the server starts shutting down.
+static SHUTDOWN_GRACE: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("SHUTDOWN_GRACE_SECS", 10)));
+
+/// How long a client has to complete the PROXY header, TLS and websocket handshakes.
+static HANDSHAKE_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("HANDSHAKE_TIMEOUT_SECS", 10)));
+
+/// Resolves once the process is asked to stop, either from a terminal or by an orchestrator.
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("received SIGTERM"),
+        _ = sigint.recv() => info!("received SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to await ctrl-c");
+}
+
+/// Runs the handshakes of an accepted socket and then its session. Spawned for every socket, so
+/// that a client stalling its handshake doesn't hold up the accept loop.
+async fn handle_connection(stream: TcpStream, tls: Option<TlsAcceptor>) {
+    let accepted = tokio::time::timeout(
+        *HANDSHAKE_TIMEOUT,
+        socket_accept::accept(stream, tls.as_ref()),
+    )
+    .await;
+
+    let (websocket, ip, settings, metadata, connection) = match accepted {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(e)) => {
+            counter!("harmony_handshake_failures_total").increment(1);
+            error!("failed to accept ws stream: {e}");
+            return;
+        }
+        Err(_) => {
+            counter!("harmony_handshake_failures_total").increment(1);
+            warn!(
+                "client did not complete its handshake within {:?}",
+                *HANDSHAKE_TIMEOUT
+            );
+            return;
+        }
+    };
+
+    counter!("harmony_connections_accepted_total").increment(1);
+    info!(
+        "accepted connection from {ip}, user agent: {:?}, origin: {:?}",
+        metadata.user_agent, metadata.origin
+    );
+
+    // held for as long as the connection is open
+    let _connection = connection;
+
+    let client = metadata.client_label();
+    gauge!("harmony_active_connections", "client" => client).increment(1.0);
+    if let Err(e) = websocket::process_events(websocket, ip, settings, metadata).await {
+        error!("process_events returned with error: {e:?}");
+    }
+    gauge!("harmony_active_connections", "client" => client).decrement(1.0);
+}
 
 async fn entry() {
+    LazyLock::force(&config::STARTED_AT);
     dotenvy::dotenv().expect("failed to load dotenv");
     env_logger::init();
     essence::connect(
@@ -28,6 +120,16 @@ async fn entry() {
     )
     .await
     .expect("essence connect failed");
+    presence::init()
+        .await
+        .expect("failed to initialize presence redis pool");
+
+    let metrics_addr = env_or("METRICS_ADDR", SocketAddr::from(([0, 0, 0, 0], 9000)));
+    PrometheusBuilder::new()
+        .with_http_listener(metrics_addr)
+        .install()
+        .expect("failed to install prometheus exporter");
+    info!("serving prometheus metrics on {metrics_addr}");
 
     let listener = TcpListener::bind("0.0.0.0:8076")
         .await
@@ -37,62 +139,139 @@ async fn entry() {
     let mut shutting_down = global_shutdown.subscribe();
 
     tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("failed to await ctrl-c");
+        wait_for_shutdown_signal().await;
         let _ = global_shutdown.send(true);
     });
 
-    let con = Connection::open(&OpenConnectionArguments::default())
+    // checked before anything else touches the broker, so a misconfiguration fails with a clear
+    // message instead of somewhere inside the amqp client
+    let amqp_args = config::amqp_connection_args_from_env()
+        .unwrap_or_else(|e| panic!("invalid amqp configuration: {e}"));
+    amqp::connect(amqp_args)
         .await
         .expect("failed to open amqp conn");
-    con.register_callback(DefaultConnectionCallback)
-        .await
-        .expect("failed to register callback for connection");
-    // events::setup({
-    //     let chan = con
-    //         .open_channel(None)
-    //         .await
-    //         .expect("failed to open amqp channel");
-    //     let _ = chan.register_callback(DefaultChannelCallback).await;
 
-    //     chan
-    // });
+    // sessions of other instances would be wiped too, so multi-instance deployments should turn
+    // this off
+    if env_or("RESET_PRESENCE_ON_START", true) {
+        presence::reset_all().await.expect("failed to reset all");
+    }
 
-    presence::reset_all().await.expect("failed to reset all");
+    {
+        let channel = amqp::open_channel()
+            .await
+            .expect("failed to open amqp channel");
+        dead_letters::setup_dead_letter_exchange(&channel)
+            .await
+            .expect("failed to set up the dead letter exchange");
+        let _ = channel.close().await;
+    }
+    tokio::spawn(dead_letters::monitor_dead_letters());
+    tokio::spawn(task_manager::monitor_health());
+    tokio::spawn(guild_load::report());
+
+    if !presence::PRESENCE_QUIET_PERIOD.is_zero() {
+        tokio::spawn(presence::flush_coalesced_presences());
+    }
+
+    let tls = tls::acceptor_from_env();
+    if tls.is_some() {
+        info!("terminating TLS at the gateway");
+    }
+
+    let mut sessions = JoinSet::new();
 
     loop {
         tokio::select! {
             socket = listener.accept() => match socket {
-                Ok((stream, local_ip)) => {
-                    match socket_accept::accept(stream).await {
-                        Ok((websocket, ip, settings)) => {
-                            let ip = ip.unwrap_or(local_ip.ip());
-                            let channel = con.open_channel(None).await.expect("failed to open amqp channel.");
-                            channel.register_callback(DefaultChannelCallback).await.expect("failed to register callback for channel");
-
-                            tokio::spawn(async move {
-                                if let Err(e) = websocket::process_events(websocket, channel, ip, settings).await {
-                                    error!("process_events returned with error: {e:?}");
-                                }
-                            });
-                        },
-                        Err(e) => {
-                            error!("failed to accept ws stream: {e}");
-                        }
-                    }
+                Ok((stream, _)) => {
+                    sessions.spawn(handle_connection(stream, tls.clone()));
                 },
                 Err(err) => error!("Couldn't accept client: {err}")
             },
+            // reap finished sessions so the set doesn't grow unbounded
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {},
             _ = shutting_down.changed() => {
                 break;
             }
         }
     }
+
+    let notified = SHUTDOWN_NOTIFIER.shutdown_all(ShutdownReason::Restart);
+    info!(
+        "shutting down, told {notified} sessions to reconnect, waiting up to {:?} for them to close",
+        *SHUTDOWN_GRACE
+    );
+
+    let drained = tokio::time::timeout(*SHUTDOWN_GRACE, async {
+        while sessions.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "{} sessions did not close within the shutdown grace period, aborting them",
+            sessions.len()
+        );
+        sessions.abort_all();
+
+        // aborted tasks resolve immediately, this only waits for them to be torn down
+        while sessions.join_next().await.is_some() {}
+    }
+
+    task_manager::TASK_MANAGER.shutdown_all();
+}
+
+/// Operator tool, run as `harmony drain-dead-letters [limit] [--republish]`. Prints up to `limit`
+/// dead-lettered messages, taking them off the queue, and optionally publishes them again.
+async fn drain_dead_letters() {
+    dotenvy::dotenv().expect("failed to load dotenv");
+    env_logger::init();
+
+    let args = std::env::args().skip(2).collect::<Vec<_>>();
+    let republish = args.iter().any(|arg| arg == "--republish");
+    let limit = args.iter().find_map(|arg| arg.parse().ok()).unwrap_or(100);
+
+    let amqp_args = config::amqp_connection_args_from_env()
+        .unwrap_or_else(|e| panic!("invalid amqp configuration: {e}"));
+    amqp::connect(amqp_args)
+        .await
+        .expect("failed to open amqp conn");
+
+    let letters = dead_letters::drain(limit)
+        .await
+        .expect("failed to drain dead letters");
+    let channel = amqp::open_channel()
+        .await
+        .expect("failed to open amqp channel");
+
+    for letter in &letters {
+        println!(
+            "session {:?} of user {:?}: {:?}, from exchange {:?} with routing key {:?}, {} bytes",
+            letter.session_id,
+            letter.user_id,
+            letter.error,
+            letter.exchange,
+            letter.routing_key,
+            letter.content.len(),
+        );
+
+        if republish {
+            match letter.republish(&channel).await {
+                Ok(true) => {}
+                Ok(false) => println!("  not republished, its origin is unknown"),
+                Err(e) => println!("  failed to republish: {e:?}"),
+            }
+        }
+    }
+    println!("drained {} dead letters", letters.len());
+    let _ = channel.close().await;
 }
 
 fn main() {
     let rt = Runtime::new().unwrap();
-    rt.block_on(entry());
+    match std::env::args().nth(1).as_deref() {
+        Some("drain-dead-letters") => rt.block_on(drain_dead_letters()),
+        _ => rt.block_on(entry()),
+    }
     rt.shutdown_timeout(Duration::from_secs(5));
 }
@@ -0,0 +1,96 @@
+use std::{sync::LazyLock, time::Duration};
+
+use amqprs::channel::{BasicAckArguments, BasicQosArguments, Channel};
+use metrics::counter;
+use tokio::time::Instant;
+
+use crate::{config::env_or, error::Result};
+
+/// How many deliveries the broker may push to a session before it has to ack some, read from
+/// `SESSION_PREFETCH`. Unlimited when 0.
+pub static SESSION_PREFETCH: LazyLock<u16> = LazyLock::new(|| env_or("SESSION_PREFETCH", 64));
+
+/// How long a session may sit at its full prefetch before it is reported as stuck.
+static STUCK_SESSION_AFTER: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("STUCK_SESSION_AFTER_SECS", 30)));
+
+/// Limits the unacked deliveries on the channel's consumers to the session prefetch.
+pub async fn set_prefetch(channel: &Channel) -> Result<()> {
+    if *SESSION_PREFETCH > 0 {
+        channel
+            .basic_qos(BasicQosArguments::new(0, *SESSION_PREFETCH, false))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The deliveries a session has taken off its queue but not acked yet. They are acked up to the
+/// latest one that was sent to the client, or all at once when there is nothing else to process,
+/// so that a slow client makes the broker hold back instead of us.
+#[derive(Default)]
+pub struct UnackedDeliveries {
+    latest: Option<u64>,
+    /// Every delivery up to this tag was acked.
+    acked: u64,
+    full_since: Option<Instant>,
+}
+
+impl UnackedDeliveries {
+    pub fn push(&mut self, delivery_tag: u64) {
+        self.latest = Some(delivery_tag);
+
+        if *SESSION_PREFETCH > 0
+            && self.outstanding() >= u64::from(*SESSION_PREFETCH)
+            && self.full_since.is_none()
+        {
+            self.full_since = Some(Instant::now());
+        }
+    }
+
+    /// Delivery tags are consecutive per channel, so this also counts the few deliveries that
+    /// were settled on their own in between.
+    fn outstanding(&self) -> u64 {
+        self.latest
+            .map_or(0, |latest| latest.saturating_sub(self.acked))
+    }
+
+    /// Acks every delivery pushed so far.
+    pub async fn ack(&mut self, channel: &Channel, session_id: &str) -> Result<()> {
+        match self.latest {
+            Some(delivery_tag) => self.ack_through(channel, session_id, delivery_tag).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Acks every delivery pushed up to and including `delivery_tag`.
+    pub async fn ack_through(
+        &mut self,
+        channel: &Channel,
+        session_id: &str,
+        delivery_tag: u64,
+    ) -> Result<()> {
+        if delivery_tag <= self.acked {
+            return Ok(());
+        }
+        self.acked = delivery_tag;
+
+        if self.outstanding() < u64::from(*SESSION_PREFETCH) {
+            if let Some(full_since) = self.full_since.take() {
+                let stalled = full_since.elapsed();
+
+                if stalled >= *STUCK_SESSION_AFTER {
+                    warn!(
+                        "session {session_id} spent {stalled:?} at its full prefetch, its client may be stuck"
+                    );
+                    counter!("harmony_stuck_sessions_total").increment(1);
+                }
+            }
+        }
+
+        channel
+            .basic_ack(BasicAckArguments::new(delivery_tag, true))
+            .await?;
+        Ok(())
+    }
+}
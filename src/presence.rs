@@ -1,33 +1,136 @@
-use std::sync::OnceLock;
+use std::{
+    sync::{LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
 
-use amqprs::channel::Channel;
-use bincode::{config::Configuration, Decode, Encode};
+use ahash::{HashSet, HashSetExt};
+use bincode::{config::Configuration, error::DecodeError, Decode, Encode};
 use chrono::{DateTime, Utc};
 use deadpool_redis::{
-    redis::{AsyncCommands, Pipeline},
-    Config, Connection, Pool, Runtime,
+    redis::{cmd, pipe, AsyncCommands, Pipeline, Script},
+    Config, Connection, Pool, PoolConfig, Runtime, Timeouts,
 };
 use essence::{
     db::{get_pool, UserDbExt},
     models::{Device, Devices, Presence, PresenceStatus},
     ws::OutboundMessage,
 };
-use futures_util::future::TryJoinAll;
+use futures_util::future::{try_join_all, TryJoinAll};
+use metrics::counter;
+use tokio::{sync::Semaphore, time::Instant};
 
-use crate::{error::Result, events::publish_user_event};
+use crate::{
+    config::env_or,
+    err_with_ctx,
+    error::Result,
+    events::{publish_bulk_event_global, publish_user_event_global},
+};
 
 static POOL: OnceLock<Pool> = OnceLock::new();
 const CONFIG: Configuration = bincode::config::standard();
 
+pub const MAX_CUSTOM_STATUS_LEN: usize = 128;
+
+/// How long a user's session list outlives its last refresh, so that sessions of a crashed
+/// instance eventually stop counting as online.
+pub static SESSION_TTL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("SESSION_TTL_SECS", 300)));
+
+/// How long a user without sessions is still reported as online, so that quick reconnects don't
+/// show up as an offline/online flap to everyone observing them.
+pub static OFFLINE_GRACE: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("OFFLINE_GRACE_SECS", 30)));
+
+/// For how long after startup presence broadcasts are held back, so that the mass reconnect after
+/// a restart doesn't flood observers. Changes made during this period are published once it ends.
+pub static PRESENCE_QUIET_PERIOD: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("PRESENCE_QUIET_PERIOD_SECS", 0)));
+
+/// How long after Ready is sent a newly identified session waits before broadcasting its presence,
+/// giving the client time to settle before the reactions of other users start arriving.
+pub static PRESENCE_SLOW_START: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("PRESENCE_SLOW_START_MS", 0)));
+
+/// How many users' presences are fetched per pipeline in [`get_presences_bulk`].
+static PRESENCE_CHUNK_SIZE: LazyLock<usize> =
+    LazyLock::new(|| env_or::<usize>("PRESENCE_CHUNK_SIZE", 256).max(1));
+/// How many of those pipelines may run at the same time, across all sessions.
+static BULK_PERMITS: LazyLock<Semaphore> =
+    LazyLock::new(|| Semaphore::new(env_or("PRESENCE_BULK_CONCURRENCY", 4)));
+
+static QUIET_UNTIL: OnceLock<Instant> = OnceLock::new();
+/// Users whose presence changed during the quiet period.
+static COALESCED: LazyLock<Mutex<HashSet<u64>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+static REDIS_CHECKOUT_RETRIES: LazyLock<u8> = LazyLock::new(|| env_or("REDIS_CHECKOUT_RETRIES", 3));
+static REDIS_CHECKOUT_RETRY_DELAY: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("REDIS_CHECKOUT_RETRY_DELAY_MS", 100)));
+/// How long waiting for, opening or health checking a presence connection may take. Without it,
+/// checkouts hang for as long as Redis is unreachable instead of failing and being retried.
+static REDIS_POOL_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("REDIS_POOL_TIMEOUT_MS", 2000)));
+
+/// Creates the presence connection pool from `REDIS_URL` and checks that Redis is reachable.
+pub async fn init() -> Result<()> {
+    let _ = QUIET_UNTIL.set(Instant::now() + *PRESENCE_QUIET_PERIOD);
+
+    let mut config = Config::from_url(std::env::var("REDIS_URL").map_err(|_| "missing REDIS_URL")?);
+    let mut pool_config = PoolConfig::new(env_or("REDIS_POOL_SIZE", 16));
+    // connections are pinged whenever they are checked out, so the ones broken by a Redis restart
+    // are replaced with fresh ones instead of being handed out
+    pool_config.timeouts = Timeouts {
+        wait: Some(*REDIS_POOL_TIMEOUT),
+        create: Some(*REDIS_POOL_TIMEOUT),
+        recycle: Some(*REDIS_POOL_TIMEOUT),
+    };
+    config.pool = Some(pool_config);
+
+    let pool = config
+        .create_pool(Some(Runtime::Tokio1))
+        .map_err(|e| err_with_ctx!(e, "create presence pool"))?;
+    let _ = POOL.set(pool);
+
+    ping_redis().await
+}
+
+/// Checks that Redis is reachable and responding.
+pub async fn ping_redis() -> Result<()> {
+    cmd("PING")
+        .query_async::<_, ()>(&mut get_con().await?)
+        .await?;
+
+    Ok(())
+}
+
 async fn get_con() -> Result<Connection> {
-    Ok(POOL
-        .get_or_init(|| {
-            Config::from_url("redis://127.0.0.1")
-                .create_pool(Some(Runtime::Tokio1))
-                .unwrap()
-        })
+    POOL.get()
+        .ok_or("presence pool used before presence::init")?
         .get()
-        .await?)
+        .await
+        .map_err(|e| err_with_ctx!(e, "failed to get a redis connection from the presence pool"))
+}
+
+/// Like [`get_con`], but retries the checkout up to `retries` more times, waiting `delay` in
+/// between, so that a brief Redis hiccup doesn't terminate the session.
+async fn get_con_with_retry(retries: u8, delay: Duration) -> Result<Connection> {
+    let mut attempt = 0;
+
+    loop {
+        match get_con().await {
+            Ok(con) => return Ok(con),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("redis checkout failed, retrying ({attempt}/{retries}): {e}");
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Checks out a connection for the paths every session goes through.
+async fn get_hot_con() -> Result<Connection> {
+    get_con_with_retry(*REDIS_CHECKOUT_RETRIES, *REDIS_CHECKOUT_RETRY_DELAY).await
 }
 
 #[derive(Debug, Encode, Decode, Clone)]
@@ -36,50 +139,248 @@ pub struct PresenceSession {
     #[bincode(with_serde)]
     pub online_since: DateTime<Utc>,
     pub device: Device,
+    /// The status chosen on this session. `None` for sessions stored by older instances, which
+    /// follow the user's status instead.
+    pub status: Option<PresenceStatus>,
+}
+
+/// A session as stored before sessions had their own status.
+#[derive(Decode)]
+#[cfg_attr(test, derive(Encode))]
+struct LegacyPresenceSession {
+    session_id: String,
+    #[bincode(with_serde)]
+    online_since: DateTime<Utc>,
+    device: Device,
 }
 
+impl PresenceSession {
+    fn decode(bytes: &[u8]) -> std::result::Result<Self, DecodeError> {
+        // the legacy layout is a prefix of the current one, so the current one has to be tried
+        // first
+        if let Ok((session, _)) = bincode::decode_from_slice(bytes, CONFIG) {
+            return Ok(session);
+        }
+
+        let legacy: LegacyPresenceSession = bincode::decode_from_slice(bytes, CONFIG)?.0;
+        Ok(Self {
+            session_id: legacy.session_id,
+            online_since: legacy.online_since,
+            device: legacy.device,
+            status: None,
+        })
+    }
+}
+
+/// How much a status takes precedence, used to pick the status shown for users with several
+/// sessions. Do not disturb comes first, so that it isn't undone by another session being online.
+fn presence_rank(status: PresenceStatus) -> u8 {
+    match status {
+        PresenceStatus::Dnd => 3,
+        PresenceStatus::Online => 2,
+        PresenceStatus::Idle => 1,
+        _ => 0,
+    }
+}
+
+/// The status that takes precedence across the sessions. Sessions without a status of their own
+/// count as `fallback`.
+fn aggregate(sessions: &[PresenceSession], fallback: PresenceStatus) -> PresenceStatus {
+    sessions
+        .iter()
+        .map(|s| s.status.unwrap_or(fallback))
+        .max_by_key(|&status| presence_rank(status))
+        .unwrap_or(fallback)
+}
+
+/// The value stored under `presence-{user_id}`.
+#[derive(Debug, Encode, Decode)]
+struct StoredPresence {
+    /// The status observers see, aggregated across the user's sessions. Sessions that went idle
+    /// count as `Idle`, so this is only `Idle` once none of them are more present.
+    status: PresenceStatus,
+    custom_status: Option<String>,
+    /// The status the user chose themselves.
+    explicit_status: PresenceStatus,
+}
+
+/// The value stored under `presence-{user_id}` before the explicit status was tracked.
+#[derive(Decode)]
+#[cfg_attr(test, derive(Encode))]
+struct LegacyStoredPresence {
+    status: PresenceStatus,
+    custom_status: Option<String>,
+}
+
+impl StoredPresence {
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        // the legacy layout is a prefix of the current one, so the current one has to be tried
+        // first
+        if let Ok((stored, _)) = bincode::decode_from_slice(bytes, CONFIG) {
+            return Ok(stored);
+        }
+
+        let legacy: LegacyStoredPresence = bincode::decode_from_slice(bytes, CONFIG)?.0;
+        Ok(Self {
+            status: legacy.status,
+            custom_status: legacy.custom_status,
+            explicit_status: legacy.status,
+        })
+    }
+}
+
+/// Validates a custom status sent by the client. An empty string clears the custom status.
+pub fn normalize_custom_status(custom_status: Option<String>) -> Result<Option<String>> {
+    match custom_status {
+        Some(custom_status) if custom_status.is_empty() => Ok(None),
+        Some(custom_status) if custom_status.chars().count() > MAX_CUSTOM_STATUS_LEN => Err(
+            format!("custom status length exceeds {MAX_CUSTOM_STATUS_LEN} characters")
+                .as_str()
+                .into(),
+        ),
+        custom_status => Ok(custom_status),
+    }
+}
+
+/// Deletes every stored session and presence. Keys are found with SCAN rather than KEYS so that
+/// Redis isn't blocked on large keyspaces.
 pub async fn reset_all() -> Result<()> {
     let mut con = get_con().await?;
 
-    let session_keys = con.keys::<_, Vec<String>>("session-*").await?;
-    let presence_keys = con.keys::<_, Vec<String>>("presence-*").await?;
+    for pattern in ["sessions:*", "session-*", "presence-*"] {
+        let mut cursor = 0_u64;
 
-    let mut pipe = Pipeline::with_capacity(session_keys.len() + presence_keys.len());
+        loop {
+            let (next, keys): (u64, Vec<String>) = cmd("SCAN")
+                .cursor_arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(500)
+                .query_async(&mut con)
+                .await?;
 
-    for key in session_keys.into_iter().chain(presence_keys.into_iter()) {
-        pipe.del(key).ignore();
-    }
+            if !keys.is_empty() {
+                let mut pipe = Pipeline::with_capacity(keys.len());
 
-    let _: () = pipe.query_async(&mut con).await?;
+                for key in keys {
+                    pipe.del(key).ignore();
+                }
+
+                let _: () = pipe.query_async(&mut con).await?;
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+    }
 
     Ok(())
 }
 
-async fn get_sessions(con: &mut Connection, key: impl AsRef<str>) -> Result<Vec<PresenceSession>> {
-    if let Some(sessions) = con
-        .lrange::<_, Option<Vec<Vec<u8>>>>(key.as_ref(), 0, -1)
-        .await?
-    {
-        if sessions.is_empty() {
-            return Ok(Vec::new());
+/// Sessions are stored in a hash keyed by session id.
+fn sessions_key(user_id: u64) -> String {
+    format!("sessions:{user_id}")
+}
+
+/// Sessions used to be stored in a list. Instances that haven't been upgraded yet still write
+/// there, so it is read alongside the hash until every instance has been rolled out.
+fn legacy_sessions_key(user_id: u64) -> String {
+    format!("session-{user_id}")
+}
+
+/// Removals of stored values that failed to decode. Without them, one corrupted value would keep
+/// breaking every read that touches it, e.g. the Ready event of everyone observing the user.
+struct Cleanup {
+    pipe: Pipeline,
+    len: usize,
+}
+
+impl Cleanup {
+    fn new() -> Self {
+        Self {
+            pipe: Pipeline::new(),
+            len: 0,
         }
+    }
 
-        let mut res = Vec::with_capacity(sessions.len());
+    fn del(&mut self, key: &str, e: impl std::fmt::Debug) {
+        warn!("removing malformed value in key {key}: {e:?}");
+        self.pipe.del(key).ignore();
+        self.len += 1;
+    }
 
-        for session in sessions.into_iter() {
-            res.push(bincode::decode_from_slice(&session, CONFIG)?.0)
+    fn hdel(&mut self, key: &str, field: &str, e: impl std::fmt::Debug) {
+        warn!("removing malformed value in key {key}, field {field}: {e:?}");
+        self.pipe.hdel(key, field).ignore();
+        self.len += 1;
+    }
+
+    fn lrem(&mut self, key: &str, value: &[u8], e: impl std::fmt::Debug) {
+        warn!("removing malformed value in key {key}: {e:?}");
+        self.pipe.lrem(key, 1, value).ignore();
+        self.len += 1;
+    }
+
+    async fn run(self, con: &mut Connection) -> Result<()> {
+        if self.len > 0 {
+            let _: () = self.pipe.query_async(con).await?;
         }
 
-        Ok(res)
-    } else {
-        Ok(Vec::new())
+        Ok(())
     }
 }
 
-pub async fn get_devices(user_id: u64) -> Result<Devices> {
+/// Decodes the sessions of a user, skipping and scheduling the removal of malformed entries.
+fn decode_sessions(
+    user_id: u64,
+    raw: Vec<(String, Vec<u8>)>,
+    legacy: Vec<Vec<u8>>,
+    cleanup: &mut Cleanup,
+) -> Vec<PresenceSession> {
+    let mut sessions = Vec::with_capacity(raw.len() + legacy.len());
+
+    for (field, session) in raw {
+        match PresenceSession::decode(&session) {
+            Ok(session) => sessions.push(session),
+            Err(e) => cleanup.hdel(&sessions_key(user_id), &field, e),
+        }
+    }
+    for raw in legacy {
+        match PresenceSession::decode(&raw) {
+            Ok(session) => sessions.push(session),
+            // removed by the bytes as stored, whichever layout they are in
+            Err(e) => cleanup.lrem(&legacy_sessions_key(user_id), &raw, e),
+        }
+    }
+
+    // hashes are unordered, so the earliest session has to be found explicitly
+    sessions.sort_unstable_by_key(|s| s.online_since);
+
+    sessions
+}
+
+/// Returns every session of the user, ordered by when they came online.
+async fn get_sessions(con: &mut Connection, user_id: u64) -> Result<Vec<PresenceSession>> {
+    let (raw, legacy): (Vec<(String, Vec<u8>)>, Vec<Vec<u8>>) = pipe()
+        .hgetall(sessions_key(user_id))
+        .lrange(legacy_sessions_key(user_id), 0, -1)
+        .query_async(con)
+        .await?;
+
+    let mut cleanup = Cleanup::new();
+    let sessions = decode_sessions(user_id, raw, legacy, &mut cleanup);
+    cleanup.run(con).await?;
+
+    Ok(sessions)
+}
+
+fn collect_devices(sessions: &[PresenceSession]) -> Devices {
     let mut devices = Devices::empty();
 
-    for session in get_sessions(&mut get_con().await?, &format!("session-{user_id}")).await? {
+    for session in sessions {
         match session.device {
             Device::Desktop => devices.insert(Devices::DESKTOP),
             Device::Mobile => devices.insert(Devices::MOBILE),
@@ -91,71 +392,213 @@ pub async fn get_devices(user_id: u64) -> Result<Devices> {
         }
     }
 
-    Ok(devices)
+    devices
 }
 
-pub async fn get_first_session(user_id: u64) -> Result<Option<PresenceSession>> {
-    let key = format!("session-{user_id}");
+pub async fn get_devices(user_id: u64) -> Result<Devices> {
+    let sessions = get_sessions(&mut get_hot_con().await?, user_id).await?;
 
-    if let Some(session) = get_con()
+    Ok(collect_devices(&sessions))
+}
+
+/// Returns the ids of the user's sessions, oldest first.
+pub async fn get_session_ids(user_id: u64) -> Result<Vec<String>> {
+    Ok(get_sessions(&mut get_con().await?, user_id)
         .await?
-        .lindex::<_, Option<Vec<u8>>>(key, 0)
+        .into_iter()
+        .map(|session| session.session_id)
+        .collect())
+}
+
+pub async fn get_first_session(user_id: u64) -> Result<Option<PresenceSession>> {
+    Ok(get_sessions(&mut get_con().await?, user_id)
         .await?
-    {
-        Ok(Some(bincode::decode_from_slice(&session, CONFIG)?.0))
-    } else {
-        Ok(None)
-    }
+        .into_iter()
+        .next())
 }
 
-pub async fn insert_session(user_id: u64, session: PresenceSession) -> Result<()> {
-    let key = format!("session-{user_id}");
+/// Counts the user's sessions, legacy ones included, and only adds the new one if there are fewer
+/// than the limit.
+static INSERT_SESSION: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('HLEN', KEYS[1]) + redis.call('LLEN', KEYS[2]) >= tonumber(ARGV[3]) then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+        redis.call('EXPIRE', KEYS[1], ARGV[4])
+        return 1
+        ",
+    )
+});
 
-    get_con()
+/// Adds the session unless the user already has `limit` of them, returning whether it was added.
+/// Counting and adding happen in a single script, so that concurrent identifies of the same user
+/// can't all see room for one more.
+pub async fn insert_session(user_id: u64, session: PresenceSession, limit: usize) -> Result<bool> {
+    Ok(INSERT_SESSION
+        .key(sessions_key(user_id))
+        .key(legacy_sessions_key(user_id))
+        .arg(&session.session_id)
+        .arg(bincode::encode_to_vec(&session, CONFIG)?)
+        .arg(limit)
+        .arg(SESSION_TTL.as_secs())
+        .invoke_async(&mut get_hot_con().await?)
+        .await?)
+}
+
+/// The status of the user across all of their sessions. Sessions stored by older instances count
+/// as `fallback`.
+pub async fn aggregate_status(user_id: u64, fallback: PresenceStatus) -> Result<PresenceStatus> {
+    let sessions = get_sessions(&mut get_hot_con().await?, user_id).await?;
+
+    Ok(aggregate(&sessions, fallback))
+}
+
+/// Changes the status of a single session, returning the resulting status of the user.
+pub async fn set_session_status(
+    user_id: u64,
+    session: &PresenceSession,
+    status: PresenceStatus,
+) -> Result<PresenceStatus> {
+    let session = PresenceSession {
+        status: Some(status),
+        ..session.clone()
+    };
+
+    get_hot_con()
         .await?
-        .rpush::<_, _, ()>(key, bincode::encode_to_vec(session, CONFIG)?)
+        .hset::<_, _, _, ()>(
+            sessions_key(user_id),
+            &session.session_id,
+            bincode::encode_to_vec(&session, CONFIG)?,
+        )
         .await?;
 
-    Ok(())
+    aggregate_status(user_id, status).await
 }
 
-pub async fn remove_session(user_id: u64, session_id: impl AsRef<str>) -> Result<()> {
+/// Recomputes the stored status of the user from their sessions, e.g. after one of them went away
+/// or went idle.
+///
+/// Returns the new effective status and custom status if anything changed.
+pub async fn recompute_status(user_id: u64) -> Result<Option<(PresenceStatus, Option<String>)>> {
+    let key = format!("presence-{user_id}");
     let mut con = get_con().await?;
-    let key = format!("session-{user_id}");
 
-    let sessions = get_sessions(&mut con, &key).await?;
+    let Some(bytes) = con.get::<_, Option<Vec<u8>>>(&key).await? else {
+        return Ok(None);
+    };
+    let mut stored = match StoredPresence::decode(&bytes) {
+        Ok(stored) => stored,
+        Err(e) => {
+            let mut cleanup = Cleanup::new();
+            cleanup.del(&key, e);
+            cleanup.run(&mut con).await?;
 
-    if sessions.len() == 1 {
-        con.del::<_, ()>(key).await?;
+            return Ok(None);
+        }
+    };
 
+    let sessions = get_sessions(&mut con, user_id).await?;
+    let status = aggregate(&sessions, stored.explicit_status);
+    if status == stored.status {
+        return Ok(None);
+    }
+    stored.status = status;
+
+    con.set::<_, _, ()>(key, bincode::encode_to_vec(&stored, CONFIG)?)
+        .await?;
+
+    Ok(Some((stored.status, stored.custom_status)))
+}
+
+/// Pushes back the expiry of the user's sessions. Called periodically by every live session.
+pub async fn refresh_sessions(user_id: u64) -> Result<()> {
+    get_con()
+        .await?
+        .expire::<_, ()>(sessions_key(user_id), SESSION_TTL.as_secs() as usize)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn remove_session(user_id: u64, session: &PresenceSession) -> Result<()> {
+    let mut con = get_con().await?;
+
+    let removed = con
+        .hdel::<_, _, usize>(sessions_key(user_id), &session.session_id)
+        .await?;
+    if removed > 0 {
         return Ok(());
     }
 
-    let index = sessions.iter().enumerate().fold(0, |acc, (i, v)| {
-        if v.session_id == session_id.as_ref() {
-            i
-        } else {
-            acc
+    // the session may have been written by an instance still using the legacy list. those are
+    // stored in the legacy layout, so re-encoding the session wouldn't match them byte for byte;
+    // the entry is found by its id and removed by the bytes as stored instead
+    let key = legacy_sessions_key(user_id);
+    let legacy = con.lrange::<_, Vec<Vec<u8>>>(&key, 0, -1).await?;
+
+    for raw in legacy {
+        if PresenceSession::decode(&raw).is_ok_and(|s| s.session_id == session.session_id) {
+            con.lrem::<_, _, ()>(&key, 1, raw).await?;
+            break;
         }
-    });
+    }
 
-    con.lset(&key, index as isize, "REMOVED").await?;
-    con.lrem(key, 1, "REMOVED").await?;
+    Ok(())
+}
+
+/// Marks the user as pending offline. `token` identifies the session that went away last, so
+/// that only its own delayed broadcast is allowed to go out.
+pub async fn schedule_offline(user_id: u64, token: &str) -> Result<()> {
+    get_con()
+        .await?
+        .set_ex::<_, _, ()>(
+            format!("offline-pending-{user_id}"),
+            token,
+            OFFLINE_GRACE.as_secs() as usize * 2 + 1,
+        )
+        .await?;
 
     Ok(())
 }
 
+/// Clears the pending offline mark, returning whether it was still owned by `token`, i.e. the
+/// user hasn't reconnected since it was set.
+pub async fn take_pending_offline(user_id: u64, token: &str) -> Result<bool> {
+    let mut con = get_con().await?;
+    let key = format!("offline-pending-{user_id}");
+
+    if con.get::<_, Option<String>>(&key).await?.as_deref() != Some(token) {
+        return Ok(false);
+    }
+    con.del::<_, ()>(key).await?;
+
+    Ok(true)
+}
+
+pub async fn count_sessions(user_id: u64) -> Result<usize> {
+    let (sessions, legacy): (usize, usize) = pipe()
+        .hlen(sessions_key(user_id))
+        .llen(legacy_sessions_key(user_id))
+        .query_async(&mut get_con().await?)
+        .await?;
+
+    Ok(sessions + legacy)
+}
+
 pub async fn any_session_exists(user_id: u64) -> Result<bool> {
-    Ok(get_con()
-        .await?
-        .llen::<_, u16>(format!("session-{user_id}"))
-        .await?
-        > 0)
+    Ok(count_sessions(user_id).await? > 0)
 }
 
+/// Stores the presence of a user. `status` is the effective status shown to others, while
+/// `explicit_status` is the one the user chose, which is kept for recomputing the effective
+/// status as sessions come and go.
 pub async fn update_presence(
     user_id: u64,
     status: PresenceStatus,
+    explicit_status: PresenceStatus,
     custom_status: Option<String>,
 ) -> Result<()> {
     let key = format!("presence-{user_id}");
@@ -167,7 +610,14 @@ pub async fn update_presence(
     } else {
         con.set(
             key,
-            bincode::encode_to_vec((status, custom_status), CONFIG)?,
+            bincode::encode_to_vec(
+                StoredPresence {
+                    status,
+                    custom_status,
+                    explicit_status,
+                },
+                CONFIG,
+            )?,
         )
         .await?;
     }
@@ -175,43 +625,442 @@ pub async fn update_presence(
     Ok(())
 }
 
-pub async fn get_presence(user_id: u64) -> Result<(PresenceStatus, Option<String>)> {
-    let key = format!("presence-{user_id}");
+/// Records that the last session of the user went away just now.
+pub async fn record_last_seen(user_id: u64) -> Result<()> {
+    get_con()
+        .await?
+        .set::<_, _, ()>(
+            format!("last-seen-{user_id}"),
+            Utc::now().timestamp_millis(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Fetches the presences of many users. Users are split into chunks that are each fetched in a
+/// single pipelined round trip, with a bounded number of chunks in flight at once so a large
+/// hydration can't drain the connection pool.
+///
+/// Users without any live session are reported as offline, regardless of their stored presence.
+pub async fn get_presences_bulk(user_ids: &[u64]) -> Result<Vec<Presence>> {
+    get_presences(user_ids, false).await
+}
+
+/// Like [`get_presences_bulk`], but for someone observing the users: those whose presence is
+/// muted are reported as offline, so that reconnecting doesn't reveal them.
+pub async fn get_observed_presences(user_ids: &[u64]) -> Result<Vec<Presence>> {
+    get_presences(user_ids, true).await
+}
+
+async fn get_presences(user_ids: &[u64], hide_muted: bool) -> Result<Vec<Presence>> {
+    let chunks = try_join_all(
+        user_ids
+            .chunks(*PRESENCE_CHUNK_SIZE)
+            .map(|chunk| async move {
+                let _permit = BULK_PERMITS
+                    .acquire()
+                    .await
+                    .map_err(|_| "presence bulk semaphore closed")?;
+
+                get_presences_chunk(chunk, hide_muted).await
+            }),
+    )
+    .await?;
+
+    Ok(chunks.into_iter().flatten().collect())
+}
+
+/// The presence of a user who has never been online, or is shown as such.
+fn offline_presence(user_id: u64) -> Presence {
+    Presence {
+        user_id,
+        status: PresenceStatus::Offline,
+        custom_status: None,
+        devices: Devices::empty(),
+        online_since: None,
+    }
+}
 
+/// Puts together the presence of a user from what is stored for them. Users without any live
+/// session, or whose presence is `hidden` from the one asking, are offline.
+fn resolve_presence(
+    user_id: u64,
+    stored: Option<StoredPresence>,
+    sessions: &[PresenceSession],
+    hidden: bool,
+) -> Presence {
+    match stored {
+        Some(stored) if !hidden && !sessions.is_empty() => Presence {
+            user_id,
+            status: stored.status,
+            custom_status: stored.custom_status,
+            devices: collect_devices(sessions),
+            online_since: sessions.first().map(|s| s.online_since),
+        },
+        _ => offline_presence(user_id),
+    }
+}
+
+async fn get_presences_chunk(user_ids: &[u64], hide_muted: bool) -> Result<Vec<Presence>> {
+    let mut pipe = Pipeline::with_capacity(user_ids.len() * 4);
+
+    for &user_id in user_ids {
+        pipe.get(format!("presence-{user_id}"))
+            .hgetall(sessions_key(user_id))
+            .lrange(legacy_sessions_key(user_id), 0, -1)
+            .exists(format!("muted-presence-{user_id}"));
+    }
+
+    let mut con = get_hot_con().await?;
+    let results: Vec<(Option<Vec<u8>>, Vec<(String, Vec<u8>)>, Vec<Vec<u8>>, bool)> =
+        pipe.query_async(&mut con).await?;
+
+    let mut presences = Vec::with_capacity(user_ids.len());
+    let mut cleanup = Cleanup::new();
+
+    for (&user_id, (presence, raw_sessions, legacy_sessions, muted)) in user_ids.iter().zip(results)
+    {
+        let sessions = decode_sessions(user_id, raw_sessions, legacy_sessions, &mut cleanup);
+        let hidden = hide_muted && muted;
+
+        let stored = match presence {
+            Some(presence) if !hidden && !sessions.is_empty() => {
+                match StoredPresence::decode(&presence) {
+                    Ok(stored) => Some(stored),
+                    Err(e) => {
+                        cleanup.del(&format!("presence-{user_id}"), e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        presences.push(resolve_presence(user_id, stored, &sessions, hidden));
+    }
+    cleanup.run(&mut con).await?;
+
+    Ok(presences)
+}
+
+/// Publishes the presence to everyone observing the user and to the user's own sessions. While
+/// the user's presence is [muted](is_presence_muted), only their own sessions receive it.
+///
+/// The last published presence is kept under `presence-last-{user_id}`, so publishes that
+/// wouldn't change anything for observers are skipped. It is swapped in with a single `SET ... GET`
+/// so that concurrent sessions can't both decide to publish the same presence.
+pub async fn publish_presence_change(user_id: u64, presence: Presence) -> Result<()> {
+    if QUIET_UNTIL
+        .get()
+        .is_some_and(|until| Instant::now() < *until)
+    {
+        COALESCED
+            .lock()
+            .expect("coalesced presences lock poisoned")
+            .insert(user_id);
+
+        return Ok(());
+    }
+
+    // observers keep seeing the user as offline, which is what `presence-last` holds while muted
+    if is_presence_muted(user_id).await? {
+        counter!("harmony_presence_publishes_total").increment(1);
+        return publish_user_event_global(user_id, OutboundMessage::PresenceUpdate { presence })
+            .await;
+    }
+
+    let key = format!("presence-last-{user_id}");
+    let encoded = bincode::encode_to_vec(&presence, CONFIG)?;
+
+    let previous: Option<Vec<u8>> = cmd("SET")
+        .arg(&key)
+        .arg(&encoded)
+        .arg("GET")
+        .query_async(&mut get_con().await?)
+        .await?;
+    if previous.as_ref() == Some(&encoded) {
+        return Ok(());
+    }
+
+    let result = fan_out_presence(user_id, presence).await;
+    if result.is_err() {
+        // let the next change publish even if it happens to match this one
+        get_con().await?.del::<_, ()>(key).await?;
+    }
+
+    result
+}
+
+/// Whether presence broadcasts of the user are paused, e.g. while they are under moderation.
+/// Their own sessions still receive their presence, and they still receive everyone else's.
+///
+/// The flag is the `muted-presence-{user_id}` key, which moderation tooling sets and deletes on
+/// its own, so users can be muted whether or not they are connected. It is kept outside of
+/// `presence-*` so that it survives [`reset_all`].
+pub async fn is_presence_muted(user_id: u64) -> Result<bool> {
     Ok(get_con()
         .await?
-        .get::<_, Option<Vec<u8>>>(key)
-        .await?
-        .map_or_else(
-            || (Default::default(), Default::default()),
-            |r| {
-                bincode::decode_from_slice(&r, CONFIG)
-                    .expect("Malformed value in key: {key}")
-                    .0
-            },
-        ))
-}
-
-pub async fn publish_presence_change(
-    channel: &Channel,
-    user_id: u64,
-    presence: Presence,
-) -> Result<()> {
+        .exists(format!("muted-presence-{user_id}"))
+        .await?)
+}
+
+/// Tells observers about a change of the user's mute flag: they see the user go offline when
+/// muted, and their current presence once unmuted. Every session of the user handles the event
+/// announcing the change, and only the first to swap `presence-last-{user_id}` publishes.
+pub async fn announce_presence_muted(user_id: u64) -> Result<()> {
+    if !is_presence_muted(user_id).await? {
+        return match get_presences_bulk(&[user_id]).await?.pop() {
+            // `presence-last` still holds the offline presence observers were shown
+            Some(presence) => publish_presence_change(user_id, presence).await,
+            None => Ok(()),
+        };
+    }
+
+    let presence = offline_presence(user_id);
+    let encoded = bincode::encode_to_vec(&presence, CONFIG)?;
+    let previous: Option<Vec<u8>> = cmd("SET")
+        .arg(format!("presence-last-{user_id}"))
+        .arg(&encoded)
+        .arg("GET")
+        .query_async(&mut get_con().await?)
+        .await?;
+    if previous.as_ref() == Some(&encoded) {
+        return Ok(());
+    }
+
+    let mut observers = get_pool()
+        .fetch_observable_user_ids_for_user(user_id)
+        .await?;
+    observers.retain(|&observer| observer != user_id);
+
+    publish_bulk_event_global(observers, OutboundMessage::PresenceUpdate { presence }).await
+}
+
+async fn fan_out_presence(user_id: u64, presence: Presence) -> Result<()> {
+    counter!("harmony_presence_publishes_total").increment(1);
+
     let mut user_ids = get_pool()
         .fetch_observable_user_ids_for_user(user_id)
         .await?;
     user_ids.push(user_id);
+    // the user may already be part of their own observable set
+    user_ids.sort_unstable();
+    user_ids.dedup();
 
-    for user_id in user_ids {
-        publish_user_event(
-            channel,
-            user_id,
-            OutboundMessage::PresenceUpdate {
-                presence: presence.clone(),
-            },
-        )
-        .await?;
+    publish_bulk_event_global(user_ids, OutboundMessage::PresenceUpdate { presence }).await
+}
+
+/// Waits for the quiet period to end, then publishes the current presence of every user whose
+/// presence changed during it.
+pub async fn flush_coalesced_presences() {
+    if let Some(until) = QUIET_UNTIL.get() {
+        tokio::time::sleep_until(*until).await;
     }
 
-    Ok(())
+    let user_ids =
+        std::mem::take(&mut *COALESCED.lock().expect("coalesced presences lock poisoned"))
+            .into_iter()
+            .collect::<Vec<_>>();
+    info!(
+        "presence quiet period over, publishing {} coalesced presences",
+        user_ids.len()
+    );
+
+    match get_presences_bulk(&user_ids).await {
+        Ok(presences) => {
+            for presence in presences {
+                let user_id = presence.user_id;
+
+                if let Err(e) = publish_presence_change(user_id, presence).await {
+                    error!("failed to publish coalesced presence for user {user_id}: {e:?}");
+                }
+            }
+        }
+        Err(e) => error!("failed to fetch coalesced presences: {e:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(device: Device) -> PresenceSession {
+        PresenceSession {
+            session_id: "session".to_string(),
+            online_since: DateTime::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            device,
+            status: None,
+        }
+    }
+
+    fn sessions_with(
+        statuses: impl IntoIterator<Item = Option<PresenceStatus>>,
+    ) -> Vec<PresenceSession> {
+        statuses
+            .into_iter()
+            .map(|status| PresenceSession {
+                status,
+                ..session(Device::Desktop)
+            })
+            .collect()
+    }
+
+    fn stored(status: PresenceStatus) -> Option<StoredPresence> {
+        Some(StoredPresence {
+            status,
+            custom_status: Some("testing".to_string()),
+            explicit_status: status,
+        })
+    }
+
+    #[test]
+    fn connected_users_have_their_stored_presence() {
+        let sessions = [session(Device::Desktop), session(Device::Mobile)];
+        let presence = resolve_presence(1234, stored(PresenceStatus::Dnd), &sessions, false);
+
+        assert_eq!(presence.status, PresenceStatus::Dnd);
+        assert_eq!(presence.custom_status.as_deref(), Some("testing"));
+        assert_eq!(presence.devices, Devices::DESKTOP | Devices::MOBILE);
+        assert_eq!(presence.online_since, Some(sessions[0].online_since));
+    }
+
+    #[test]
+    fn muted_users_are_offline_to_observers() {
+        let sessions = [session(Device::Web)];
+        let presence = resolve_presence(1234, stored(PresenceStatus::Online), &sessions, true);
+
+        assert_eq!(presence.status, PresenceStatus::Offline);
+        assert!(presence.custom_status.is_none());
+        assert!(presence.devices.is_empty());
+        assert!(presence.online_since.is_none());
+    }
+
+    #[test]
+    fn users_without_sessions_are_offline() {
+        let presence = resolve_presence(1234, stored(PresenceStatus::Online), &[], false);
+
+        assert_eq!(presence.status, PresenceStatus::Offline);
+        assert!(presence.devices.is_empty());
+    }
+
+    #[test]
+    fn statuses_take_precedence_in_order() {
+        use PresenceStatus::{Dnd, Idle, Offline, Online};
+
+        let ranked = [Offline, Idle, Online, Dnd];
+        for pair in ranked.windows(2) {
+            assert!(presence_rank(pair[0]) < presence_rank(pair[1]), "{pair:?}");
+        }
+
+        let aggregated = |statuses: &[PresenceStatus]| {
+            aggregate(&sessions_with(statuses.iter().copied().map(Some)), Offline)
+        };
+        assert_eq!(aggregated(&[Online, Dnd, Idle]), Dnd);
+        assert_eq!(aggregated(&[Idle, Online]), Online);
+        assert_eq!(aggregated(&[Idle, Idle]), Idle);
+        // sessions that appear offline don't hide the others
+        assert_eq!(aggregated(&[Offline, Idle]), Idle);
+        assert_eq!(aggregated(&[Offline]), Offline);
+    }
+
+    #[test]
+    fn sessions_without_status_follow_the_fallback() {
+        use PresenceStatus::{Dnd, Idle, Offline, Online};
+
+        assert_eq!(
+            aggregate(&sessions_with([None, Some(Idle)]), Online),
+            Online
+        );
+        assert_eq!(aggregate(&sessions_with([None, Some(Online)]), Dnd), Dnd);
+        assert_eq!(aggregate(&sessions_with([None]), Offline), Offline);
+        // without sessions, the user is whatever they chose
+        assert_eq!(aggregate(&[], Idle), Idle);
+    }
+
+    const MALFORMED: &[u8] = b"\xff\xff\xff";
+
+    fn legacy_session(session_id: &str, online_since: i64) -> Vec<u8> {
+        let legacy = LegacyPresenceSession {
+            session_id: session_id.to_string(),
+            online_since: DateTime::from_timestamp_millis(online_since).unwrap(),
+            device: Device::Mobile,
+        };
+
+        bincode::encode_to_vec(legacy, CONFIG).unwrap()
+    }
+
+    #[test]
+    fn sessions_decode_from_either_layout() {
+        let current = PresenceSession {
+            status: Some(PresenceStatus::Idle),
+            ..session(Device::Web)
+        };
+        let decoded =
+            PresenceSession::decode(&bincode::encode_to_vec(&current, CONFIG).unwrap()).unwrap();
+        assert_eq!(decoded.session_id, current.session_id);
+        assert_eq!(decoded.status, Some(PresenceStatus::Idle));
+
+        let legacy = PresenceSession::decode(&legacy_session("legacy", 1_000)).unwrap();
+        assert_eq!(legacy.session_id, "legacy");
+        assert!(matches!(legacy.device, Device::Mobile));
+        assert_eq!(legacy.status, None);
+
+        assert!(PresenceSession::decode(MALFORMED).is_err());
+        assert!(PresenceSession::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn stored_presences_decode_from_either_layout() {
+        let current = StoredPresence {
+            status: PresenceStatus::Idle,
+            custom_status: None,
+            explicit_status: PresenceStatus::Online,
+        };
+        let decoded =
+            StoredPresence::decode(&bincode::encode_to_vec(current, CONFIG).unwrap()).unwrap();
+        assert_eq!(decoded.status, PresenceStatus::Idle);
+        assert_eq!(decoded.explicit_status, PresenceStatus::Online);
+
+        let legacy = LegacyStoredPresence {
+            status: PresenceStatus::Dnd,
+            custom_status: Some("busy".to_string()),
+        };
+        let decoded =
+            StoredPresence::decode(&bincode::encode_to_vec(legacy, CONFIG).unwrap()).unwrap();
+        assert_eq!(decoded.status, PresenceStatus::Dnd);
+        assert_eq!(decoded.custom_status.as_deref(), Some("busy"));
+        // legacy presences were always chosen by the user
+        assert_eq!(decoded.explicit_status, PresenceStatus::Dnd);
+
+        assert!(StoredPresence::decode(MALFORMED).is_err());
+    }
+
+    #[test]
+    fn malformed_sessions_are_skipped_and_cleaned_up() {
+        let current = PresenceSession {
+            session_id: "current".to_string(),
+            online_since: DateTime::from_timestamp_millis(2_000).unwrap(),
+            ..session(Device::Desktop)
+        };
+        let raw = vec![
+            (
+                "current".to_string(),
+                bincode::encode_to_vec(&current, CONFIG).unwrap(),
+            ),
+            ("broken".to_string(), MALFORMED.to_vec()),
+        ];
+        let legacy = vec![MALFORMED.to_vec(), legacy_session("legacy", 1_000)];
+
+        let mut cleanup = Cleanup::new();
+        let sessions = decode_sessions(1234, raw, legacy, &mut cleanup);
+
+        // ordered by when they came online, regardless of where they are stored
+        let ids = sessions
+            .iter()
+            .map(|s| s.session_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["legacy", "current"]);
+        assert_eq!(cleanup.len, 2);
+    }
 }
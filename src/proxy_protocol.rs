@@ -0,0 +1,242 @@
+//! Minimal PROXY protocol (v1 and v2) support for deployments behind HAProxy or an AWS NLB,
+//! where the client address is prepended to the TCP stream instead of being sent as a header.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+use crate::{config::env_or, socket_accept::is_trusted_proxy};
+
+/// Whether PROXY headers are read at all. Anyone able to connect directly could otherwise spoof
+/// their address, so this has to be turned on explicitly, in addition to listing the load
+/// balancers in `TRUSTED_PROXIES`.
+static ENABLED: LazyLock<bool> = LazyLock::new(|| env_or("PROXY_PROTOCOL", false));
+
+/// Whether a PROXY header sent by the given peer should be read. Only load balancers listed in
+/// `TRUSTED_PROXIES` may send one, so nothing is read from anyone while that is unset.
+pub fn is_trusted(peer: IpAddr) -> bool {
+    *ENABLED && is_trusted_proxy(&peer)
+}
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest v2 header we accept. Addresses take at most 216 bytes, the rest leaves room for
+/// the TLVs load balancers append.
+const V2_MAX_LEN: usize = 4096;
+
+/// How long to wait for more of a header that arrived split across segments before peeking again.
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY header: {msg}"),
+    )
+}
+
+/// What the start of a stream holds.
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    /// The stream doesn't start with a PROXY header.
+    NoHeader,
+    /// The stream starts like a PROXY header, but not all of it has arrived yet.
+    Incomplete,
+    /// A complete header of `len` bytes, carrying the source address if there is one.
+    Header { len: usize, ip: Option<IpAddr> },
+}
+
+/// Parses the PROXY header at the start of `buf`, if there is one.
+fn parse(buf: &[u8]) -> io::Result<Parsed> {
+    let starts_with = |prefix: &[u8]| {
+        let len = buf.len().min(prefix.len());
+        buf[..len] == prefix[..len]
+    };
+
+    if starts_with(V1_PREFIX) {
+        if buf.len() < V1_PREFIX.len() {
+            return Ok(Parsed::Incomplete);
+        }
+        parse_v1(buf)
+    } else if starts_with(V2_SIGNATURE) {
+        if buf.len() < V2_SIGNATURE.len() {
+            return Ok(Parsed::Incomplete);
+        }
+        parse_v2(buf)
+    } else {
+        Ok(Parsed::NoHeader)
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Parsed> {
+    let searched = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = searched.windows(2).position(|w| w == b"\r\n") else {
+        return if buf.len() >= V1_MAX_LEN {
+            Err(invalid("v1 header is not terminated"))
+        } else {
+            Ok(Parsed::Incomplete)
+        };
+    };
+
+    let header = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("not utf-8"))?;
+    let mut parts = header.split(' ').skip(1);
+
+    let ip = match parts.next() {
+        Some("TCP4" | "TCP6") => parts
+            .next()
+            .and_then(|ip| ip.parse().ok())
+            .map(Some)
+            .ok_or_else(|| invalid("bad v1 source address"))?,
+        Some("UNKNOWN") => None,
+        _ => return Err(invalid("unknown v1 protocol")),
+    };
+
+    Ok(Parsed::Header { len: end + 2, ip })
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Parsed> {
+    if buf.len() < 16 {
+        return Ok(Parsed::Incomplete);
+    }
+
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    if 16 + len > V2_MAX_LEN {
+        return Err(invalid("v2 header is too long"));
+    }
+    let Some(addresses) = buf.get(16..16 + len) else {
+        return Ok(Parsed::Incomplete);
+    };
+
+    // LOCAL connections (health checks) carry no address
+    let ip = if version_command & 0x0F == 0 {
+        None
+    } else {
+        match family >> 4 {
+            1 if len >= 12 => {
+                let octets: [u8; 4] = addresses[..4].try_into().unwrap();
+
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            2 if len >= 36 => {
+                let octets: [u8; 16] = addresses[..16].try_into().unwrap();
+
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            // unix sockets and unspecified families have no ip to report
+            0 | 3 => None,
+            _ => return Err(invalid("bad v2 address block")),
+        }
+    };
+
+    Ok(Parsed::Header { len: 16 + len, ip })
+}
+
+/// Consumes the PROXY header from the stream if there is one, returning the source address it
+/// carries. Streams without a header are left untouched.
+///
+/// A header may arrive split across segments, in which case this keeps peeking until all of it
+/// is there. It doesn't give up on its own, callers are expected to put a deadline on it.
+pub async fn read_header(stream: &mut TcpStream) -> io::Result<Option<IpAddr>> {
+    let mut buf = vec![0; V1_MAX_LEN.max(V2_MAX_LEN)];
+
+    loop {
+        let peeked = stream.peek(&mut buf).await?;
+        if peeked == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        match parse(&buf[..peeked])? {
+            Parsed::NoHeader => return Ok(None),
+            Parsed::Header { len, ip } => {
+                stream.read_exact(&mut buf[..len]).await?;
+                return Ok(ip);
+            }
+            // peeking again right away would only see the same bytes
+            Parsed::Incomplete => tokio::time::sleep(PEEK_INTERVAL).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1_HEADER: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+
+    fn v2_header(addresses: &[u8], len: u16) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        // version 2, PROXY command, TCP over IPv4
+        header.extend([0x21, 0x11]);
+        header.extend(len.to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    const V2_ADDRESSES: [u8; 12] = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+
+    #[test]
+    fn valid_v1() {
+        let stream = [V1_HEADER, b"GET / HTTP/1.1\r\n"].concat();
+
+        assert_eq!(
+            parse(&stream).unwrap(),
+            Parsed::Header {
+                len: V1_HEADER.len(),
+                ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            }
+        );
+    }
+
+    #[test]
+    fn valid_v2() {
+        let mut stream = v2_header(&V2_ADDRESSES, 12);
+        let len = stream.len();
+        stream.extend(b"GET / HTTP/1.1\r\n");
+
+        assert_eq!(
+            parse(&stream).unwrap(),
+            Parsed::Header {
+                len,
+                ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            }
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(parse(b"PRO").unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(&V1_HEADER[..20]).unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(&V2_SIGNATURE[..5]).unwrap(), Parsed::Incomplete);
+        assert_eq!(
+            parse(&v2_header(&V2_ADDRESSES[..4], 12)).unwrap(),
+            Parsed::Incomplete
+        );
+    }
+
+    #[test]
+    fn unterminated_v1() {
+        let stream = [b"PROXY TCP4 ".as_slice(), &[b'1'; V1_MAX_LEN]].concat();
+
+        assert!(parse(&stream).is_err());
+    }
+
+    #[test]
+    fn unknown_signature() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n").unwrap(), Parsed::NoHeader);
+        // start of a TLS client hello
+        assert_eq!(parse(&[0x16, 0x03, 0x01]).unwrap(), Parsed::NoHeader);
+        assert!(parse(b"PROXY FOO\r\n").is_err());
+    }
+}
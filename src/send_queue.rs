@@ -0,0 +1,200 @@
+use std::sync::LazyLock;
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Mutex,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{config::env_or, prefetch::SESSION_PREFETCH};
+
+/// How many events may be waiting to be sent to a session before some are dropped.
+///
+/// Queued events stay unacked until they are sent, so with a session prefetch the broker stops
+/// delivering once that many are waiting. The queue has to be smaller than the prefetch for it to
+/// ever fill up, which is why it defaults to half of it.
+pub static SEND_QUEUE_CAPACITY: LazyLock<usize> = LazyLock::new(|| {
+    let prefetch = usize::from(*SESSION_PREFETCH);
+    if prefetch == 0 {
+        return env_or::<usize>("SEND_QUEUE_CAPACITY", 512).max(1);
+    }
+
+    let capacity = env_or("SEND_QUEUE_CAPACITY", prefetch / 2).max(1);
+    if capacity >= prefetch && prefetch > 1 {
+        warn!(
+            "SEND_QUEUE_CAPACITY {capacity} is not below SESSION_PREFETCH {prefetch} and could never \
+             fill up, using {} instead",
+            prefetch - 1
+        );
+        return prefetch - 1;
+    }
+    capacity
+});
+
+/// Which event is dropped once a session's send queue is full, read from `SEND_QUEUE_OVERFLOW`
+/// as either `drop-oldest` or `drop-newest`.
+pub static SEND_QUEUE_OVERFLOW: LazyLock<Overflow> =
+    LazyLock::new(|| match std::env::var("SEND_QUEUE_OVERFLOW").as_deref() {
+        Ok("drop-newest") => Overflow::DropNewest,
+        Ok("drop-oldest") | Err(_) => Overflow::DropOldest,
+        Ok(other) => {
+            warn!("unknown SEND_QUEUE_OVERFLOW {other}, dropping the oldest events");
+            Overflow::DropOldest
+        }
+    });
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the event that has been waiting the longest, keeping the client as up to date as
+    /// possible.
+    DropOldest,
+    /// Drop the event that was about to be queued, keeping what the client receives contiguous up
+    /// to the point it fell behind.
+    DropNewest,
+}
+
+impl Overflow {
+    /// Which event is dropped, for logs.
+    pub const fn dropped(self) -> &'static str {
+        match self {
+            Self::DropOldest => "oldest",
+            Self::DropNewest => "newest",
+        }
+    }
+}
+
+/// An encoded event waiting to be sent, along with the delivery it came from so that it can be
+/// acked once sent.
+pub struct Outgoing {
+    pub message: Message,
+    pub delivery_tag: u64,
+}
+
+/// Events waiting to be sent to a session's client. Bounded, so that a client reading slower
+/// than its events arrive costs a fixed amount of memory instead of all of it.
+pub struct SendQueue {
+    tx: mpsc::Sender<Outgoing>,
+    /// Locked by the writer while it waits for an event, and by [`SendQueue::push`] to make room
+    /// when dropping the oldest event.
+    rx: Mutex<mpsc::Receiver<Outgoing>>,
+    overflow: Overflow,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self::with_capacity(*SEND_QUEUE_CAPACITY, *SEND_QUEUE_OVERFLOW)
+    }
+
+    fn with_capacity(capacity: usize, overflow: Overflow) -> Self {
+        let (tx, rx) = mpsc::channel(capacity);
+
+        Self {
+            tx,
+            rx: Mutex::new(rx),
+            overflow,
+        }
+    }
+
+    /// Queues the event, dropping one according to [`SEND_QUEUE_OVERFLOW`] if the queue is full.
+    /// Returns whether an event was dropped.
+    pub async fn push(&self, outgoing: Outgoing) -> bool {
+        let mut outgoing = match self.tx.try_send(outgoing) {
+            Ok(()) => return false,
+            // the receiver lives as long as the queue
+            Err(TrySendError::Closed(_)) => return false,
+            Err(TrySendError::Full(outgoing)) => outgoing,
+        };
+        if self.overflow == Overflow::DropNewest {
+            return true;
+        }
+
+        let mut rx = self.rx.lock().await;
+        let mut dropped = false;
+        loop {
+            outgoing = match self.tx.try_send(outgoing) {
+                Ok(()) | Err(TrySendError::Closed(_)) => return dropped,
+                Err(TrySendError::Full(outgoing)) => outgoing,
+            };
+
+            // the writer may have taken one while we waited for the lock, in which case nothing
+            // has to be dropped anymore
+            dropped |= rx.try_recv().is_ok();
+        }
+    }
+
+    /// Waits for the next event to send.
+    pub async fn pop(&self) -> Outgoing {
+        self.rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .expect("the sender lives as long as the queue")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tx.capacity() == self.tx.max_capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outgoing(delivery_tag: u64) -> Outgoing {
+        Outgoing {
+            message: Message::Text(delivery_tag.to_string()),
+            delivery_tag,
+        }
+    }
+
+    async fn fill(queue: &SendQueue, tags: impl IntoIterator<Item = u64>) {
+        for tag in tags {
+            assert!(
+                !queue.push(outgoing(tag)).await,
+                "dropped an event below capacity"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest_events() {
+        let queue = SendQueue::with_capacity(3, Overflow::DropOldest);
+        fill(&queue, 1..=3).await;
+
+        assert!(queue.push(outgoing(4)).await);
+        assert!(queue.push(outgoing(5)).await);
+        for tag in 3..=5 {
+            assert_eq!(queue.pop().await.delivery_tag, tag);
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_oldest_events() {
+        let queue = SendQueue::with_capacity(3, Overflow::DropNewest);
+        fill(&queue, 1..=3).await;
+
+        assert!(queue.push(outgoing(4)).await);
+        for tag in 1..=3 {
+            assert_eq!(queue.pop().await.delivery_tag, tag);
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn room_is_made_once_events_are_sent() {
+        let queue = SendQueue::with_capacity(2, Overflow::DropNewest);
+        assert!(queue.is_empty());
+
+        fill(&queue, 1..=2).await;
+        assert!(!queue.is_empty());
+        assert_eq!(queue.pop().await.delivery_tag, 1);
+
+        // the popped event freed a slot, so nothing is dropped
+        fill(&queue, [3]).await;
+        assert_eq!(queue.pop().await.delivery_tag, 2);
+        assert_eq!(queue.pop().await.delivery_tag, 3);
+        assert!(queue.is_empty());
+    }
+}
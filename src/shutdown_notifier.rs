@@ -0,0 +1,142 @@
+use std::sync::{LazyLock, Mutex};
+
+use ahash::{HashMap, HashMapExt};
+use tokio::sync::oneshot::{self, Receiver, Sender};
+use uuid::Uuid;
+
+/// Why a session is being shut down from outside of its own task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The session was forcibly terminated and should not simply reconnect.
+    Terminated,
+    /// This instance is going away; the client should reconnect, most likely to another instance.
+    Restart,
+    /// The user's token was revoked, so the client has to identify with a new one.
+    TokenRevoked,
+}
+
+pub static SHUTDOWN_NOTIFIER: LazyLock<ShutdownNotifier> = LazyLock::new(ShutdownNotifier::new);
+
+/// Tracks every active session so that it can be forcibly disconnected from outside of its own
+/// task, e.g. by an admin or when the broker closes its channel.
+pub struct ShutdownNotifier {
+    senders: Mutex<HashMap<Uuid, Sender<ShutdownReason>>>,
+}
+
+impl ShutdownNotifier {
+    fn new() -> Self {
+        Self {
+            senders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a session, returning a receiver that resolves once the session should shut down.
+    pub fn insert(&self, session_id: Uuid) -> Receiver<ShutdownReason> {
+        let (tx, rx) = oneshot::channel();
+
+        self.senders
+            .lock()
+            .expect("shutdown notifier lock poisoned")
+            .insert(session_id, tx);
+
+        rx
+    }
+
+    /// Tells a session to shut down. Returns `false` if no session with this id is registered.
+    pub fn shutdown(&self, session_id: &Uuid, reason: ShutdownReason) -> bool {
+        let sender = self
+            .senders
+            .lock()
+            .expect("shutdown notifier lock poisoned")
+            .remove(session_id);
+
+        sender.is_some_and(|tx| tx.send(reason).is_ok())
+    }
+
+    /// Tells every registered session to shut down, returning how many were notified.
+    pub fn shutdown_all(&self, reason: ShutdownReason) -> usize {
+        let senders = std::mem::take(
+            &mut *self
+                .senders
+                .lock()
+                .expect("shutdown notifier lock poisoned"),
+        );
+
+        senders
+            .into_values()
+            .filter(|tx| !tx.is_closed())
+            .map(|tx| tx.send(reason))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// Deregisters a session without notifying it.
+    pub fn remove(&self, session_id: &Uuid) {
+        self.senders
+            .lock()
+            .expect("shutdown notifier lock poisoned")
+            .remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Barrier;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_inserts_and_shutdowns() {
+        const SESSIONS: usize = 64;
+
+        let notifier = Arc::new(ShutdownNotifier::new());
+        let registered = Arc::new(Barrier::new(SESSIONS + 1));
+
+        let sessions = (0..SESSIONS)
+            .map(|i| {
+                let notifier = notifier.clone();
+                let registered = registered.clone();
+
+                tokio::spawn(async move {
+                    let session_id = Uuid::new_v4();
+                    let rx = notifier.insert(session_id);
+                    if i % 2 == 0 {
+                        assert!(notifier.shutdown(&session_id, ShutdownReason::Terminated));
+                    }
+                    registered.wait().await;
+
+                    (i, rx.await.unwrap())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        registered.wait().await;
+        // sessions that were already shut down aren't notified again
+        assert_eq!(notifier.shutdown_all(ShutdownReason::Restart), SESSIONS / 2);
+
+        for session in sessions {
+            let (i, reason) = session.await.unwrap();
+            let expected = if i % 2 == 0 {
+                ShutdownReason::Terminated
+            } else {
+                ShutdownReason::Restart
+            };
+            assert_eq!(reason, expected);
+        }
+        assert!(notifier.senders.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn removed_sessions_are_not_notified() {
+        let notifier = ShutdownNotifier::new();
+        let session_id = Uuid::new_v4();
+        let mut rx = notifier.insert(session_id);
+
+        notifier.remove(&session_id);
+        assert!(!notifier.shutdown(&session_id, ShutdownReason::Terminated));
+        // the sender is gone along with the registration
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,49 +1,422 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, str::FromStr, sync::LazyLock};
 
 use qstring::QString;
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::{
-    accept_hdr_async, tungstenite::handshake::server::Request, WebSocketStream as _WebSocketStream,
+    accept_hdr_async_with_config,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request},
+        http::{HeaderMap, HeaderName, StatusCode},
+        protocol::WebSocketConfig,
+    },
+    WebSocketStream as _WebSocketStream,
+};
+
+use crate::{
+    config::{env_or, ConnectionSettings, Intents, MessageFormat, DEFAULT_VERSION},
+    connection_limiter::{ConnectionGuard, CONNECTION_LIMITER},
 };
 
-use crate::config::{ConnectionSettings, DEFAULT_VERSION};
+/// The stream a websocket runs over, either plain TCP or TCP wrapped in TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type WebSocketStream = _WebSocketStream<Box<dyn Stream>>;
+
+/// Limits applied to every websocket, so a single client can't make us buffer unbounded
+/// amounts of data in either direction.
+static WEBSOCKET_CONFIG: LazyLock<WebSocketConfig> = LazyLock::new(|| {
+    let max_message_size = env_or("MAX_WS_MESSAGE_SIZE", 65536);
+
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        max_write_buffer_size: env_or("MAX_WS_WRITE_BUFFER_SIZE", 16 * 1024 * 1024),
+        ..Default::default()
+    }
+});
+
+/// Whether unknown `format` values are rejected instead of falling back to JSON. Off by default so
+/// that existing clients relying on the fallback keep working.
+static STRICT_MESSAGE_FORMAT: LazyLock<bool> =
+    LazyLock::new(|| env_or("STRICT_MESSAGE_FORMAT", false));
+
+/// An address range a trusted proxy connects from, either a single address or a CIDR block such
+/// as `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug)]
+struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr = addr.parse::<IpAddr>().map_err(|_| ())?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| ())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Proxies allowed to tell us the client ip through [`TRUSTED_PROXY_HEADERS`] or a PROXY protocol
+/// header, read from the comma-separated `TRUSTED_PROXIES` as addresses or CIDR blocks. None are
+/// trusted when unset.
+///
+/// Anyone connecting directly can send these headers with any address they like, so handling
+/// them, `X-Forwarded-For` in particular, is only safe for peers listed here.
+static TRUSTED_PROXIES: LazyLock<Vec<IpRange>> = LazyLock::new(|| {
+    std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .filter_map(|range| match range.parse() {
+            Ok(range) => Some(range),
+            Err(()) => {
+                warn!("ignoring invalid address range in TRUSTED_PROXIES: {range}");
+                None
+            }
+        })
+        .collect()
+});
+
+pub fn is_trusted_proxy(ip: &IpAddr) -> bool {
+    TRUSTED_PROXIES.iter().any(|range| range.contains(ip))
+}
+
+/// Headers the client ip is read from, in order of trust, read from the comma-separated
+/// `TRUSTED_PROXY_HEADERS`. They are only read from peers in [`TRUSTED_PROXIES`].
+static TRUSTED_PROXY_HEADERS: LazyLock<Vec<HeaderName>> = LazyLock::new(|| {
+    std::env::var("TRUSTED_PROXY_HEADERS")
+        .unwrap_or_else(|_| "cf-connecting-ip,x-real-ip,x-forwarded-for".to_string())
+        .split(',')
+        .filter_map(|name| match name.trim().parse::<HeaderName>() {
+            Ok(name) => Some(name),
+            Err(_) => {
+                warn!("ignoring invalid header name in TRUSTED_PROXY_HEADERS: {name}");
+                None
+            }
+        })
+        .collect()
+});
+
+/// Whether connections without a client ip from a trusted proxy are rejected. Connections coming
+/// straight from a peer that isn't a trusted proxy are rejected as well.
+static REQUIRE_PROXY_IP: LazyLock<bool> = LazyLock::new(|| env_or("REQUIRE_PROXY_IP", false));
+
+/// Whether the address belongs to a private or otherwise non-routable range, i.e. it was most
+/// likely added by one of our own proxies.
+fn is_internal(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local, fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // link local, fe80::/10
+                || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+fn ip_from_header(headers: &HeaderMap, name: &HeaderName) -> Option<IpAddr> {
+    let value = headers.get(name)?.to_str().ok()?;
+
+    if *name == "x-forwarded-for" {
+        // every proxy appends the address it received the request from, and the client can put
+        // anything in front of that. walking back from the right, the first address that isn't
+        // one of our own proxies is the client
+        for ip in value.rsplit(',') {
+            let ip = ip.trim().parse::<IpAddr>().ok()?;
+
+            if !is_trusted_proxy(&ip) && !is_internal(&ip) {
+                return Some(ip);
+            }
+        }
+        None
+    } else {
+        value.trim().parse().ok()
+    }
+}
+
+/// Origins allowed to connect, read from the comma-separated `ALLOWED_ORIGINS`. Any origin is
+/// allowed when unset.
+static ALLOWED_ORIGINS: LazyLock<Option<Vec<String>>> = LazyLock::new(|| {
+    std::env::var("ALLOWED_ORIGINS").ok().map(|origins| {
+        origins
+            .split(',')
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect()
+    })
+});
+
+/// Clients that get their own `client` label on connection metrics, from the comma separated
+/// `METRICS_CLIENTS`. They are matched against the product at the start of the user agent, such
+/// as `adapt-desktop` in `adapt-desktop/1.2.0`.
+static KNOWN_CLIENTS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    std::env::var("METRICS_CLIENTS")
+        .map(|clients| {
+            clients
+                .split(',')
+                .map(|client| client.trim().to_ascii_lowercase())
+                .filter(|client| !client.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+/// Information about the client captured during the handshake, kept for abuse investigation.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionMetadata {
+    pub user_agent: Option<String>,
+    pub origin: Option<String>,
+    pub ip: Option<IpAddr>,
+}
 
-pub type WebSocketStream = _WebSocketStream<TcpStream>;
+impl ConnectionMetadata {
+    /// Which client this is, for labelling metrics. The user agent is up to the client, so
+    /// anything outside of [`KNOWN_CLIENTS`] is counted as `browser` or `other` to keep the number
+    /// of labels bounded.
+    pub fn client_label(&self) -> &'static str {
+        client_label(self.user_agent.as_deref(), &KNOWN_CLIENTS)
+    }
+}
+
+fn client_label<'a>(user_agent: Option<&str>, known: &'a [String]) -> &'a str {
+    let Some(product) = user_agent.and_then(|user_agent| user_agent.split(['/', ' ']).next())
+    else {
+        return "other";
+    };
+
+    match known
+        .iter()
+        .find(|client| client.eq_ignore_ascii_case(product))
+    {
+        Some(client) => client,
+        None if product == "Mozilla" => "browser",
+        None => "other",
+    }
+}
 
 pub async fn accept(
-    stream: TcpStream,
+    #[allow(unused_mut)] mut stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
 ) -> Result<
-    (WebSocketStream, Option<IpAddr>, ConnectionSettings),
+    (
+        WebSocketStream,
+        IpAddr,
+        ConnectionSettings,
+        ConnectionMetadata,
+        ConnectionGuard,
+    ),
     tokio_tungstenite::tungstenite::Error,
 > {
-    let mut ip = None;
+    let mut client_ip = None;
     let mut settings = ConnectionSettings::default();
+    let mut metadata = ConnectionMetadata::default();
+    let mut connection = None;
+    let peer_ip = stream.peer_addr()?.ip();
 
-    let websocket = accept_hdr_async(stream, |req: &Request, resp| {
-        ip = req
-            .headers()
-            .get("cf-connecting-ip")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse::<IpAddr>().ok());
-
-        if let Some(query) = req.uri().query() {
-            let queries = QString::from(query);
-
-            let version = queries
-                .get("version")
-                .and_then(|v| v.parse::<u8>().ok())
-                .unwrap_or(DEFAULT_VERSION);
-            let format = queries
-                .get("format")
-                .and_then(|f| f.parse().ok())
-                .unwrap_or_default();
-
-            settings = ConnectionSettings { version, format };
-        }
+    #[cfg(feature = "proxy-protocol")]
+    let proxied_ip = if crate::proxy_protocol::is_trusted(peer_ip) {
+        crate::proxy_protocol::read_header(&mut stream).await?
+    } else {
+        None
+    };
+    #[cfg(not(feature = "proxy-protocol"))]
+    let proxied_ip: Option<IpAddr> = None;
 
-        Ok(resp)
-    })
+    // the PROXY header precedes the TLS handshake
+    let stream: Box<dyn Stream> = match tls {
+        Some(tls) => Box::new(tls.accept(stream).await?),
+        None => Box::new(stream),
+    };
+
+    let websocket = accept_hdr_async_with_config(
+        stream,
+        |req: &Request, resp| {
+            let ip = if is_trusted_proxy(&peer_ip) {
+                TRUSTED_PROXY_HEADERS
+                    .iter()
+                    .find_map(|name| ip_from_header(req.headers(), name))
+            } else {
+                None
+            };
+
+            if *REQUIRE_PROXY_IP && ip.is_none() && proxied_ip.is_none() {
+                let mut resp = ErrorResponse::new(Some("missing client ip".to_string()));
+                *resp.status_mut() = StatusCode::BAD_REQUEST;
+
+                return Err(resp);
+            }
+
+            // the address from the PROXY header comes straight from the load balancer, so it is
+            // trusted over any header. `ip` is only set for trusted proxies, so the client can't pick
+            // the address it is counted and identified under
+            let verified_ip = proxied_ip.or(ip).unwrap_or(peer_ip).to_canonical();
+            client_ip = Some(verified_ip);
+            connection = CONNECTION_LIMITER.try_acquire(verified_ip);
+            if connection.is_none() {
+                let mut resp = ErrorResponse::new(Some("too many connections".to_string()));
+                *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+
+                return Err(resp);
+            }
+
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(ToString::to_string)
+            };
+            metadata = ConnectionMetadata {
+                user_agent: header("user-agent"),
+                origin: header("origin"),
+                ip: Some(verified_ip),
+            };
+
+            if let Some(allowed) = &*ALLOWED_ORIGINS {
+                if !metadata
+                    .origin
+                    .as_ref()
+                    .is_some_and(|origin| allowed.contains(origin))
+                {
+                    let mut resp = ErrorResponse::new(Some("origin not allowed".to_string()));
+                    *resp.status_mut() = StatusCode::FORBIDDEN;
+
+                    return Err(resp);
+                }
+            }
+
+            if let Some(query) = req.uri().query() {
+                let queries = QString::from(query);
+
+                let version = queries
+                    .get("version")
+                    .and_then(|v| v.parse::<u8>().ok())
+                    // unsupported versions are rejected once upgraded, see `process_events`
+                    .unwrap_or(DEFAULT_VERSION);
+                let format = match queries.get("format") {
+                    Some(format) if *STRICT_MESSAGE_FORMAT => {
+                        let Some(format) = MessageFormat::parse_strict(format) else {
+                            let mut resp = ErrorResponse::new(Some(format!(
+                                "unsupported format {format}, expected one of: {}",
+                                MessageFormat::VALID.join(", ")
+                            )));
+                            *resp.status_mut() = StatusCode::BAD_REQUEST;
+
+                            return Err(resp);
+                        };
+
+                        format
+                    }
+                    format => format.and_then(|f| f.parse().ok()).unwrap_or_default(),
+                };
+
+                let presence_fields = queries
+                    .get("presence_fields")
+                    .and_then(|f| f.parse().ok())
+                    .unwrap_or_default();
+
+                let intents = queries
+                    .get("intents")
+                    .and_then(|i| i.parse().ok())
+                    .map(Intents::from_bits_truncate)
+                    .unwrap_or_default();
+
+                let gateway_info = queries
+                    .get("gateway_info")
+                    .is_some_and(|v| v.eq_ignore_ascii_case("true"));
+
+                settings = ConnectionSettings {
+                    version,
+                    format,
+                    presence_fields,
+                    intents,
+                    gateway_info,
+                };
+            }
+
+            Ok(resp)
+        },
+        Some(*WEBSOCKET_CONFIG),
+    )
     .await?;
 
-    Ok((websocket, ip, settings))
+    // both only unset if the handshake failed, which already returned
+    let connection = connection.expect("connection guard not acquired during handshake");
+    let client_ip = client_ip.expect("client ip not resolved during handshake");
+
+    Ok((websocket, client_ip, settings, metadata, connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_labels_are_bounded() {
+        let known = ["adapt-desktop".to_string()];
+
+        assert_eq!(
+            client_label(Some("adapt-desktop/1.2.0 (linux)"), &known),
+            "adapt-desktop"
+        );
+        assert_eq!(
+            client_label(Some("Adapt-Desktop/2.0.0"), &known),
+            "adapt-desktop"
+        );
+        assert_eq!(
+            client_label(
+                Some("Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101 Firefox/128.0"),
+                &known
+            ),
+            "browser"
+        );
+        assert_eq!(client_label(Some("my-bot/0.1"), &known), "other");
+        assert_eq!(client_label(Some(""), &known), "other");
+        assert_eq!(client_label(None, &known), "other");
+    }
 }
@@ -0,0 +1,217 @@
+use std::{
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, LazyLock, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use ahash::{HashMap, HashMapExt};
+use futures_util::FutureExt;
+use metrics::gauge;
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+pub static TASK_MANAGER: LazyLock<TaskManager> = LazyLock::new(TaskManager::new);
+
+const RUNNING: u8 = 0;
+const COMPLETED: u8 = 1;
+const PANICKED: u8 = 2;
+
+/// What became of a task spawned through the [`TaskManager`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskStatus {
+    Running,
+    Completed,
+    /// The task panicked, with the panic message.
+    Panicked(String),
+}
+
+/// Updated by a task once it exits.
+#[derive(Default)]
+struct TaskHealth {
+    status: AtomicU8,
+    panic: OnceLock<String>,
+}
+
+impl TaskHealth {
+    fn status(&self) -> TaskStatus {
+        match self.status.load(Ordering::Acquire) {
+            RUNNING => TaskStatus::Running,
+            COMPLETED => TaskStatus::Completed,
+            _ => TaskStatus::Panicked(self.panic.get().cloned().unwrap_or_default()),
+        }
+    }
+}
+
+/// Keeps track of the background tasks spawned for each session, so that none of them outlive
+/// the connection they belong to.
+pub struct TaskManager {
+    tasks: Mutex<HashMap<Uuid, Vec<(AbortHandle, Arc<TaskHealth>)>>>,
+}
+
+impl TaskManager {
+    fn new() -> Self {
+        Self {
+            tasks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spawns a task owned by the given session.
+    pub fn spawn<F>(&self, session_id: Uuid, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let health = Arc::new(TaskHealth::default());
+        let handle = tokio::spawn({
+            let health = health.clone();
+
+            async move {
+                match AssertUnwindSafe(future).catch_unwind().await {
+                    Ok(()) => health.status.store(COMPLETED, Ordering::Release),
+                    Err(panic) => {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(ToString::to_string)
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| "unknown panic".to_string());
+
+                        let _ = health.panic.set(message);
+                        health.status.store(PANICKED, Ordering::Release);
+                    }
+                }
+            }
+        })
+        .abort_handle();
+
+        self.tasks
+            .lock()
+            .expect("task manager lock poisoned")
+            .entry(session_id)
+            .or_default()
+            .push((handle, health));
+    }
+
+    /// Reports the status of every task owned by the given session, in the order they were
+    /// spawned.
+    pub fn health_check(&self, session_id: &Uuid) -> Vec<(usize, TaskStatus)> {
+        self.tasks
+            .lock()
+            .expect("task manager lock poisoned")
+            .get(session_id)
+            .map(|tasks| {
+                tasks
+                    .iter()
+                    .map(|(_, health)| health.status())
+                    .enumerate()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sessions with at least one task that panicked.
+    pub fn unhealthy_sessions(&self) -> Vec<Uuid> {
+        let session_ids = self
+            .tasks
+            .lock()
+            .expect("task manager lock poisoned")
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+
+        session_ids
+            .into_iter()
+            .filter(|session_id| {
+                self.health_check(session_id)
+                    .iter()
+                    .any(|(_, status)| matches!(status, TaskStatus::Panicked(_)))
+            })
+            .collect()
+    }
+
+    /// Aborts every task owned by the given session.
+    pub fn shutdown(&self, session_id: &Uuid) {
+        let handles = self
+            .tasks
+            .lock()
+            .expect("task manager lock poisoned")
+            .remove(session_id);
+
+        for (handle, _) in handles.into_iter().flatten() {
+            handle.abort();
+        }
+    }
+
+    /// Aborts every task of every session.
+    pub fn shutdown_all(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().expect("task manager lock poisoned"));
+
+        for (handle, _) in tasks.into_values().flatten() {
+            handle.abort();
+        }
+    }
+}
+
+/// Periodically reports how many sessions have a task that panicked.
+pub async fn monitor_health() {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+
+    loop {
+        interval.tick().await;
+
+        let unhealthy = TASK_MANAGER.unhealthy_sessions();
+        gauge!("harmony_unhealthy_sessions").set(unhealthy.len() as f64);
+
+        for session_id in unhealthy {
+            debug!(
+                "session {session_id} has panicked tasks: {:?}",
+                TASK_MANAGER.health_check(&session_id)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn panics_are_captured() {
+        let manager = TaskManager::new();
+        let session_id = Uuid::new_v4();
+        let (tx, rx) = oneshot::channel::<()>();
+
+        manager.spawn(session_id, async { panic!("boom") });
+        manager.spawn(session_id, async {});
+        manager.spawn(session_id, async move {
+            let _tx = tx;
+            std::future::pending::<()>().await;
+        });
+
+        while manager.health_check(&session_id)[..2]
+            .iter()
+            .any(|(_, status)| *status == TaskStatus::Running)
+        {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            manager.health_check(&session_id),
+            [
+                (0, TaskStatus::Panicked("boom".to_string())),
+                (1, TaskStatus::Completed),
+                (2, TaskStatus::Running),
+            ]
+        );
+        assert_eq!(manager.unhealthy_sessions(), [session_id]);
+
+        // the task still running is aborted, which drops its sender
+        manager.shutdown(&session_id);
+        assert!(rx.await.is_err());
+        assert!(manager.health_check(&session_id).is_empty());
+        assert!(manager.unhealthy_sessions().is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+//! TLS termination at the gateway, for deployments without a proxy in front of it doing so.
+
+use std::{fs::File, io, io::BufReader, sync::Arc};
+
+use rustls_pemfile::Item;
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Builds the acceptor from the PEM files at `TLS_CERT_PATH` and `TLS_KEY_PATH`. Connections are
+/// accepted over plain TCP unless both are set.
+pub fn acceptor_from_env() -> Option<TlsAcceptor> {
+    match (
+        std::env::var("TLS_CERT_PATH"),
+        std::env::var("TLS_KEY_PATH"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => Some(
+            load(&cert_path, &key_path)
+                .unwrap_or_else(|e| panic!("failed to load TLS certificate: {e}")),
+        ),
+        (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
+            warn!("only one of TLS_CERT_PATH and TLS_KEY_PATH is set, TLS stays disabled");
+            None
+        }
+        _ => None,
+    }
+}
+
+fn load(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {cert_path}")));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("no private key found in {key_path}")))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
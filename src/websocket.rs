@@ -1,8 +1,16 @@
-use std::{net::IpAddr, time::Duration};
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use ahash::{HashSet, HashSetExt};
-use amqprs::channel::{
-    BasicConsumeArguments, Channel, ConsumerMessage, QueueBindArguments, QueueDeclareArguments,
+use ahash::{HashMap, HashMapExt};
+use amqprs::{
+    channel::{
+        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, Channel, ConsumerMessage,
+        QueueBindArguments, QueueDeclareArguments,
+    },
+    FieldTable, FieldValue,
 };
 use essence::{
     calculate_permissions, calculate_permissions_sorted,
@@ -15,30 +23,78 @@ use essence::{
     ws::{InboundMessage, OutboundMessage},
 };
 use futures_util::{future::TryJoinAll, SinkExt, StreamExt, TryStreamExt};
-use tokio::sync::Mutex;
+use metrics::{counter, gauge};
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, Mutex},
+    time::Instant,
+};
 use tokio_tungstenite::tungstenite::{
     protocol::{frame::coding::CloseCode, CloseFrame},
     Message,
 };
+use uuid::Uuid;
 
 use crate::{
     bail, bail_with_ctx,
-    config::{ConnectionSettings, UserSession},
+    bandwidth::{is_low_priority, ByteBucket},
+    callbacks::ChannelCallbacks,
+    close_codes::GatewayCloseCode,
+    config::{
+        ConnectionSettings, Intents, SessionLimitPolicy, UserSession, BUILD_COMMIT,
+        HEARTBEAT_INTERVAL, HIDDEN_CHANNELS_RESYNC, IDLE_AFTER, MAX_PENDING_PONGS,
+        MAX_SESSIONS_PER_USER, SESSION_LIMIT_POLICY, SESSION_MESSAGE_TTL, SESSION_QUEUE_EXPIRY,
+        STARTED_AT, SUPPORTED_VERSIONS,
+    },
+    dead_letters::{dead_letter, DEAD_LETTER_EXCHANGE},
     err_with_ctx,
     error::{Error, Result},
-    events::{subscribe, unsubscribe, CONFIG},
+    events::{bind_user, subscribe, unsubscribe, InternalEvent, CONFIG},
+    guild_load,
+    identify_limiter::IDENTIFY_LIMITER,
+    prefetch::{set_prefetch, UnackedDeliveries},
     presence::{
-        any_session_exists, get_devices, get_first_session, get_presence, insert_session,
-        publish_presence_change, remove_session, update_presence, PresenceSession,
+        aggregate_status, announce_presence_muted, any_session_exists, count_sessions, get_devices,
+        get_first_session, get_observed_presences, get_presences_bulk, get_session_ids,
+        insert_session, normalize_custom_status, publish_presence_change, recompute_status,
+        record_last_seen, refresh_sessions, remove_session, schedule_offline, set_session_status,
+        take_pending_offline, update_presence, PresenceSession, OFFLINE_GRACE, PRESENCE_SLOW_START,
+        SESSION_TTL,
     },
-    socket_accept::WebSocketStream,
+    send_queue::{Outgoing, SendQueue, SEND_QUEUE_OVERFLOW},
+    shutdown_notifier::{ShutdownReason, SHUTDOWN_NOTIFIER},
+    socket_accept::{ConnectionMetadata, WebSocketStream},
+    task_manager::TASK_MANAGER,
 };
 
+/// Events of the gateway itself, which have no counterpart in [`OutboundMessage`].
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum GatewayNotice {
+    /// Sent right after `Hello` to clients that asked for it, for diagnostics and compatibility
+    /// checks.
+    GatewayInfo {
+        version: &'static str,
+        protocol_versions: &'static [u8],
+        commit: Option<&'static str>,
+        uptime_secs: u64,
+    },
+}
+
+fn gateway_info() -> GatewayNotice {
+    GatewayNotice::GatewayInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_versions: SUPPORTED_VERSIONS,
+        commit: BUILD_COMMIT,
+        uptime_secs: STARTED_AT.elapsed().as_secs(),
+    }
+}
+
 async fn update_hidden_channels(
     guild_id: u64,
     user_id: u64,
     channel_id: u64,
-    hidden_channels: &mut HashSet<u64>,
+    hidden_channels: &mut HashMap<u64, u64>,
     roles: impl AsMut<[Role]>,
     overwrites: Option<&[PermissionOverwrite]>,
 ) -> Result<()> {
@@ -52,21 +108,275 @@ async fn update_hidden_channels(
     if perm.contains(Permissions::VIEW_CHANNEL) {
         hidden_channels.remove(&channel_id);
     } else {
-        hidden_channels.insert(channel_id);
+        hidden_channels.insert(channel_id, guild_id);
     }
 
     Ok(())
 }
 
+/// Marks the session as idle, or back from being idle by passing the status chosen on it. The
+/// user's presence is published if that changed it, i.e. an idle session doesn't make the user
+/// idle while another one of theirs is still active.
+async fn apply_idle(user_id: u64, session: &PresenceSession, status: PresenceStatus) -> Result<()> {
+    set_session_status(user_id, session, status).await?;
+
+    if let Some((status, custom_status)) = recompute_status(user_id).await? {
+        publish_current_presence(user_id, status, custom_status).await?;
+    }
+
+    Ok(())
+}
+
+async fn publish_current_presence(
+    user_id: u64,
+    status: PresenceStatus,
+    custom_status: Option<String>,
+) -> Result<()> {
+    publish_presence_change(
+        user_id,
+        Presence {
+            user_id,
+            status,
+            custom_status,
+            devices: get_devices(user_id).await?,
+            online_since: get_first_session(user_id).await?.map(|s| s.online_since),
+        },
+    )
+    .await
+}
+
+/// Makes room for another session of the user if they are at [`MAX_SESSIONS_PER_USER`], returning
+/// whether the new session may go ahead. Concurrent identifies can all pass this, so the limit is
+/// checked again when the session is inserted.
+async fn make_room_for_session(user_id: u64) -> Result<bool> {
+    if count_sessions(user_id).await? < *MAX_SESSIONS_PER_USER {
+        return Ok(true);
+    }
+    if *SESSION_LIMIT_POLICY == SessionLimitPolicy::Reject {
+        return Ok(false);
+    }
+
+    // only sessions of this instance can be closed from here
+    for session_id in get_session_ids(user_id).await? {
+        if let Ok(session_id) = session_id.parse::<Uuid>() {
+            if SHUTDOWN_NOTIFIER.shutdown(&session_id, ShutdownReason::Terminated) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Computes the full set of channels the user is not allowed to view across all of their guilds,
+/// mapped to the guild each is in, along with the owner of each guild keyed by guild id.
+async fn compute_hidden_channels(user_id: u64) -> Result<(HashMap<u64, u64>, HashMap<u64, u64>)> {
+    let guilds = get_pool()
+        .fetch_all_guilds_for_user(
+            user_id,
+            GetGuildQuery {
+                channels: true,
+                roles: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let mut hidden = HashMap::new();
+    let mut owners = HashMap::with_capacity(guilds.len());
+
+    for guild in guilds {
+        owners.insert(guild.partial.id, guild.partial.owner_id);
+        if guild.partial.owner_id == user_id {
+            continue;
+        }
+
+        let Some(channels) = guild.channels else {
+            continue;
+        };
+        if channels.is_empty() {
+            continue;
+        }
+
+        let base_permissions = get_pool()
+            .fetch_member_by_id(guild.partial.id, user_id)
+            .await?
+            .ok_or("member not found while creating hidden_channels")?
+            .permissions;
+
+        let mut roles = guild.roles.unwrap_or_default();
+        roles.sort_unstable_by_key(|r| r.position);
+
+        for channel in channels {
+            let perm = calculate_permissions_sorted(
+                user_id,
+                base_permissions,
+                &roles,
+                Some(&channel.overwrites),
+            );
+
+            if !perm.contains(Permissions::VIEW_CHANNEL) {
+                hidden.insert(channel.id, guild.partial.id);
+            }
+        }
+    }
+
+    Ok((hidden, owners))
+}
+
+/// Declares the session's queue, binds it to everything the user receives events from and starts
+/// consuming from it.
+async fn consume_events(
+    amqp: &Channel,
+    session: &UserSession,
+    ip: IpAddr,
+) -> Result<UnboundedReceiver<ConsumerMessage>> {
+    // messages the session can't process are nacked into the dead letter exchange
+    let mut arguments = FieldTable::new();
+    arguments.insert(
+        "x-dead-letter-exchange".try_into().unwrap(),
+        FieldValue::S(DEAD_LETTER_EXCHANGE.try_into().unwrap()),
+    );
+    // auto-delete only kicks in once the consumer is cancelled, which never happens if the
+    // instance is killed, so the broker also expires the queue and its messages on its own
+    arguments.insert(
+        "x-expires".try_into().unwrap(),
+        FieldValue::I(SESSION_QUEUE_EXPIRY.as_millis() as i32),
+    );
+    arguments.insert(
+        "x-message-ttl".try_into().unwrap(),
+        FieldValue::I(SESSION_MESSAGE_TTL.as_millis() as i32),
+    );
+
+    // TODO: Resume, disable auto-delete for queues
+    if let Err(e) = amqp
+        .queue_declare(
+            QueueDeclareArguments::transient_autodelete(session.queue_name())
+                .arguments(arguments)
+                .finish(),
+        )
+        .await
+    {
+        bail_with_ctx!(e, "declare queue: queue_declare");
+    }
+
+    match get_pool()
+        .fetch_all_guild_ids_for_user(session.user_id)
+        .await
+    {
+        Ok(guilds) => {
+            for guild in guilds {
+                if let Err(e) = subscribe(
+                    amqp,
+                    guild,
+                    session.queue_name(),
+                    "topic",
+                    session.settings.intents,
+                )
+                .await
+                {
+                    bail_with_ctx!(e, "subscribe to guilds: subscribe");
+                }
+            }
+        }
+        Err(e) => {
+            bail_with_ctx!(e, "fetch guild ids: fetch_all_guild_ids_for_user");
+        }
+    }
+
+    match get_pool()
+        .fetch_all_dm_channels_for_user(session.user_id)
+        .await
+    {
+        Ok(dm_channels) => {
+            for channel in dm_channels {
+                if let Err(e) = subscribe(
+                    amqp,
+                    channel.id,
+                    session.queue_name(),
+                    "topic",
+                    session.settings.intents,
+                )
+                .await
+                {
+                    bail_with_ctx!(e, "subscribe to dm channels: subscribe");
+                }
+            }
+        }
+        Err(e) => {
+            bail_with_ctx!(e, "fetch dm channels: fetch_all_dm_channels_for_user");
+        }
+    }
+
+    if let Err(e) = amqp
+        .queue_bind(QueueBindArguments {
+            queue: session.queue_name().to_string(),
+            exchange: "events".to_string(),
+            // other services may still publish bulk events listing several user ids in their
+            // routing key
+            routing_key: format!("#.{}.#", session.user_id),
+            ..Default::default()
+        })
+        .await
+    {
+        bail_with_ctx!(e, "bind queue: queue_bind");
+    }
+
+    if let Err(e) = bind_user(amqp, session.queue_name(), session.user_id).await {
+        bail_with_ctx!(e, "bind queue: bind_user");
+    }
+
+    if let Err(e) = set_prefetch(amqp).await {
+        bail_with_ctx!(e, "channel consume: set_prefetch");
+    }
+
+    match amqp
+        .basic_consume_rx(
+            BasicConsumeArguments::new(
+                session.queue_name(),
+                &format!(
+                    "consumer-{}-{}-{}",
+                    session.user_id,
+                    session.get_session_id_str(),
+                    ip
+                ),
+            )
+            .manual_ack(true)
+            .finish(),
+        )
+        .await
+    {
+        Ok((_, amqp_rx)) => Ok(amqp_rx),
+        Err(e) => {
+            bail_with_ctx!(e, "channel consume: basic_consume_rx");
+        }
+    }
+}
+
 pub async fn process_events(
     websocket: WebSocketStream,
-    amqp: Channel,
     ip: IpAddr,
     settings: ConnectionSettings,
+    metadata: ConnectionMetadata,
 ) -> Result<()> {
     let (tx, mut rx) = websocket.split();
     let tx = Mutex::new(tx);
 
+    // rejected after the upgrade rather than during it, since browsers don't let clients see why
+    // an upgrade failed but do pass on close codes
+    if !SUPPORTED_VERSIONS.contains(&settings.version) {
+        let _ = tx
+            .lock()
+            .await
+            .send(Message::Close(Some(
+                GatewayCloseCode::InvalidApiVersion
+                    .close_frame(format!("unsupported gateway version {}", settings.version)),
+            )))
+            .await;
+
+        return Ok(());
+    }
+
     if let Err(e) = tx
         .lock()
         .await
@@ -77,6 +387,12 @@ pub async fn process_events(
         bail_with_ctx!(e, "failed to send hello event: tx.send");
     }
 
+    if settings.gateway_info {
+        if let Err(e) = tx.lock().await.send(settings.encode(&gateway_info())).await {
+            bail_with_ctx!(e, "failed to send gateway info: tx.send");
+        }
+    }
+
     let identify = {
         if let Ok(Ok(Some(mut message))) =
             tokio::time::timeout(Duration::from_secs(5), rx.try_next()).await
@@ -85,25 +401,27 @@ pub async fn process_events(
             match identify {
                 Ok(identify) => identify,
                 Err(e) => {
+                    counter!("harmony_identify_failures_total", "reason" => "decode").increment(1);
                     let _ = tx
                         .lock()
                         .await
-                        .send(Message::Close(Some(CloseFrame {
-                            code: CloseCode::Error,
-                            reason: format!("deser error: {e:?}").into(),
-                        })))
+                        .send(Message::Close(Some(
+                            GatewayCloseCode::DecodeError
+                                .close_frame(format!("deser error: {e:?}")),
+                        )))
                         .await;
                     bail_with_ctx!(e, "deserialize identify event: settings.decode");
                 }
             }
         } else {
+            counter!("harmony_identify_failures_total", "reason" => "timeout").increment(1);
             let _ = tx
                 .lock()
                 .await
-                .send(Message::Close(Some(CloseFrame {
-                    code: CloseCode::Policy,
-                    reason: "expected to receive `identify` event within 5 seconds".into(),
-                })))
+                .send(Message::Close(Some(
+                    GatewayCloseCode::SessionTimedOut
+                        .close_frame("expected to receive `identify` event within 5 seconds"),
+                )))
                 .await;
 
             return Err(crate::error::Error::default()
@@ -118,79 +436,205 @@ pub async fn process_events(
         device,
     } = identify
     {
+        // held until Ready is sent, since that's where the database is hit the hardest
+        let Some(identify_permit) = IDENTIFY_LIMITER.acquire(ip).await else {
+            counter!("harmony_identify_failures_total", "reason" => "rate_limited").increment(1);
+            let _ = tx
+                .lock()
+                .await
+                .send(Message::Close(Some(
+                    GatewayCloseCode::RateLimited.close_frame("too many concurrent identifies"),
+                )))
+                .await;
+            bail!("too many concurrent identifies");
+        };
+
         let session = match UserSession::new(settings, token).await {
             Ok(Some(session)) => session,
             Ok(None) => {
+                counter!("harmony_identify_failures_total", "reason" => "invalid_token")
+                    .increment(1);
                 let _ = tx
                     .lock()
                     .await
-                    .send(Message::Close(Some(CloseFrame {
-                        code: CloseCode::Error,
-                        reason: "invalid token".into(),
-                    })))
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::AuthenticationFailed.close_frame("invalid token"),
+                    )))
                     .await;
                 bail!("invalid token")
             }
             Err(e) => {
+                counter!("harmony_identify_failures_total", "reason" => "db_error").increment(1);
                 let _ = tx
                     .lock()
                     .await
-                    .send(Message::Close(Some(CloseFrame {
-                        code: CloseCode::Error,
-                        reason: format!("db error: {e:?}").into(),
-                    })))
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::InternalError.close_frame(format!("db error: {e:?}")),
+                    )))
                     .await;
                 bail!("invalid token");
             }
         };
 
-        let inner = async {
-            let online_since = chrono::Utc::now();
+        let custom_status = match normalize_custom_status(custom_status) {
+            Ok(custom_status) => custom_status,
+            Err(e) => {
+                counter!("harmony_identify_failures_total", "reason" => "invalid_payload")
+                    .increment(1);
+                if let Err(e) = tx
+                    .lock()
+                    .await
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::InvalidPayload.close_frame(e.to_string()),
+                    )))
+                    .await
+                {
+                    warn!("failed to send: {e:?}");
+                }
 
-            if let Err(e) = insert_session(
-                session.user_id,
-                PresenceSession {
-                    session_id: session.get_session_id_str().to_string(),
-                    online_since,
-                    device,
-                },
-            )
-            .await
-            {
+                return Err(e);
+            }
+        };
+
+        match make_room_for_session(session.user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                counter!("harmony_identify_failures_total", "reason" => "too_many_sessions")
+                    .increment(1);
                 let _ = tx
                     .lock()
                     .await
-                    .send(Message::Close(Some(CloseFrame {
-                        code: CloseCode::Error,
-                        reason: format!("redis error: {e:?}").into(),
-                    })))
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::TooManySessions.close_frame("too many sessions"),
+                    )))
+                    .await;
+                bail!("too many sessions");
+            }
+            Err(e) => {
+                let _ = tx
+                    .lock()
+                    .await
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::InternalError.close_frame(format!("redis error: {e:?}")),
+                    )))
                     .await;
+                bail_with_ctx!(e, "make_room_for_session");
+            }
+        }
 
-                bail_with_ctx!(e, "insert_session");
+        // only opened once identified, so that connections that never get this far don't hold
+        // on to a channel
+        let amqp = match crate::amqp::open_channel().await {
+            Ok(channel) => channel,
+            Err(e) => {
+                let _ = tx
+                    .lock()
+                    .await
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::InternalError.close_frame(format!("amqp error: {e:?}")),
+                    )))
+                    .await;
+                bail_with_ctx!(e, "open session channel: open_channel");
             }
+        };
+
+        info!(
+            "session {} established for user {} from {ip}, user agent: {:?}, origin: {:?}",
+            session.get_session_id_str(),
+            session.user_id,
+            metadata.user_agent,
+            metadata.origin,
+        );
+        counter!("harmony_sessions_established_total").increment(1);
+        gauge!("harmony_active_sessions").increment(1.0);
+
+        let shutdown_rx = SHUTDOWN_NOTIFIER.insert(session.session_id);
+        amqp.register_callback(ChannelCallbacks::new(
+            session.session_id,
+            session.get_session_id_str(),
+        ))
+        .await?;
+
+        // whether this session is idle
+        let idle = AtomicBool::new(false);
+        let identified_presence = (status, custom_status.clone());
 
-            if let Some(status) = &custom_status {
-                if status.len() > 256 {
-                    if let Err(e) = tx
+        let presence_session = PresenceSession {
+            session_id: session.get_session_id_str().to_string(),
+            online_since: chrono::Utc::now(),
+            device,
+            status: Some(status),
+        };
+
+        TASK_MANAGER.spawn(session.session_id, {
+            let user_id = session.user_id;
+
+            async move {
+                let mut interval = tokio::time::interval(*SESSION_TTL / 3);
+
+                loop {
+                    interval.tick().await;
+
+                    if let Err(e) = refresh_sessions(user_id).await {
+                        warn!("failed to refresh sessions of user {user_id}: {e}");
+                    }
+                }
+            }
+        });
+
+        let inner = async {
+            let online_since = presence_session.online_since;
+
+            // make_room_for_session only checked, so the limit is enforced for good here. sessions
+            // closed to make room may not be gone yet though
+            let limit = match *SESSION_LIMIT_POLICY {
+                SessionLimitPolicy::Reject => *MAX_SESSIONS_PER_USER,
+                SessionLimitPolicy::CloseOldest => usize::MAX,
+            };
+            match insert_session(session.user_id, presence_session.clone(), limit).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    counter!("harmony_identify_failures_total", "reason" => "too_many_sessions")
+                        .increment(1);
+                    let _ = tx
                         .lock()
                         .await
-                        .send(Message::Close(
-                            Some(CloseFrame {
-                                code: CloseCode::Policy,
-                                reason: "status length exceeds 256 characters".into() 
-                        }))).await {
-                            warn!("failed to send: {e:?}");
-                    }
+                        .send(Message::Close(Some(
+                            GatewayCloseCode::TooManySessions.close_frame("too many sessions"),
+                        )))
+                        .await;
+                    bail!("too many sessions");
+                }
+                Err(e) => {
+                    let _ = tx
+                        .lock()
+                        .await
+                        .send(Message::Close(Some(
+                            GatewayCloseCode::InternalError
+                                .close_frame(format!("redis error: {e:?}")),
+                        )))
+                        .await;
 
-                    return Err("status length exceeds 256 characters".into());
+                    bail_with_ctx!(e, "insert_session");
                 }
             }
 
-            if let Err(e) = update_presence(session.user_id, status, custom_status.clone()).await {
+            // other sessions of the user may be more present than this one
+            let explicit_status = status;
+            let status = aggregate_status(session.user_id, status)
+                .await
+                .map_err(|e| err_with_ctx!(e, "aggregate_status"))?;
+            if let Err(e) = update_presence(
+                session.user_id,
+                status,
+                explicit_status,
+                custom_status.clone(),
+            )
+            .await
+            {
                 bail_with_ctx!(e, "update_presence");
             }
 
-            info!("publishing presence change");
             let presence = Presence {
                 user_id: session.user_id,
                 status,
@@ -202,43 +646,38 @@ pub async fn process_events(
                         .map_or_else(|| online_since, |s| s.online_since),
                 ),
             };
-            if let Err(e) = publish_presence_change(&amqp, session.user_id, presence.clone()).await {
-                bail_with_ctx!(e, "publish_presence_change");
-            }
-
-            info!("published user {}'s presence.", session.user_id);
-
             let presences = {
-                let users = get_pool()
+                let mut users = get_pool()
                     .fetch_observable_user_ids_for_user(session.user_id)
                     .await
                     .map_err(|e| {
                         err_with_ctx!(e, "fetch presences: fetch_observable_user_ids_for_user")
                     })?;
-
-                let mut presences = Vec::with_capacity(users.len());
-                presences.push(presence);
-
-                for user_id in users {
-                    if user_id == session.user_id {
-                        continue;
-                    }
-                    let presence_status = get_presence(user_id).await?;
-
-                    presences.push(Presence {
-                        user_id,
-                        status: presence_status.0,
-                        custom_status: presence_status.1,
-                        devices: get_devices(user_id).await?,
-                        online_since: get_first_session(user_id)
-                            .await?
-                            .map_or_else(|| None, |s| Some(s.online_since)),
-                    });
+                users.retain(|&user_id| user_id != session.user_id);
+                // without the presences intent, only the user's own presence is sent
+                if !session.settings.intents.contains(Intents::PRESENCES) {
+                    users.clear();
                 }
 
+                let mut presences = Vec::with_capacity(users.len() + 1);
+                presences.push(presence.clone());
+                presences.extend(
+                    get_observed_presences(&users)
+                        .await
+                        .map_err(|e| err_with_ctx!(e, "fetch presences: get_observed_presences"))?,
+                );
+
                 presences
             };
 
+            let presences = presences
+                .into_iter()
+                .map(|mut presence| {
+                    session.settings.strip_presence(&mut presence);
+                    presence
+                })
+                .collect();
+
             match session.get_ready_event(presences).await {
                 Ok(ready) => {
                     if let Err(e) = tx.lock().await.send(session.encode(&ready)).await {
@@ -249,157 +688,227 @@ pub async fn process_events(
                     bail_with_ctx!(e, "generate ready event: session.get_ready_event");
                 }
             }
+            drop(identify_permit);
 
-            // TODO: Resume, disable auto-delete for queues
-            if let Err(e) = amqp
-                .queue_declare(QueueDeclareArguments::transient_autodelete(
-                    session.get_session_id_str(),
-                ))
-                .await
-            {
-                bail_with_ctx!(e, "declare queue: queue_declare");
+            let mut amqp_rx = consume_events(&amqp, &session, ip).await?;
+
+            // only broadcast once the client has Ready and the session queue is bound, so that
+            // neither misses the reactions of other users
+            if !PRESENCE_SLOW_START.is_zero() {
+                tokio::time::sleep(*PRESENCE_SLOW_START).await;
+            }
+            info!("publishing presence change");
+            if let Err(e) = publish_presence_change(session.user_id, presence).await {
+                bail_with_ctx!(e, "publish_presence_change");
             }
+            info!("published user {}'s presence.", session.user_id);
 
-            match get_pool()
-                .fetch_all_guild_ids_for_user(session.user_id)
-                .await
-            {
-                Ok(guilds) => {
-                    for guild in guilds {
-                        if let Err(e) =
-                            subscribe(&amqp, guild, session.get_session_id_str(), "topic").await
-                        {
-                            bail_with_ctx!(e, "subscribe to guilds: subscribe");
-                        }
+            let (mut hidden_channels, mut guild_owners) =
+                match compute_hidden_channels(session.user_id).await {
+                    Ok(computed) => computed,
+                    Err(e) => {
+                        bail_with_ctx!(e, "create hidden_channels: compute_hidden_channels")
                     }
-                }
-                Err(e) => {
-                    bail_with_ctx!(e, "fetch guild ids: fetch_all_guild_ids_for_user");
-                }
-            }
+                };
 
-            match get_pool()
-                .fetch_all_dm_channels_for_user(session.user_id)
-                .await
-            {
-                Ok(dm_channels) => {
-                    for channel in dm_channels {
-                        if let Err(e) =
-                            subscribe(&amqp, channel.id, session.get_session_id_str(), "topic")
-                                .await
+            // the consumer buffers without bound, so events are processed into a bounded queue
+            // that the writer sends from. a client reading slower than its events arrive would
+            // otherwise make us hold on to all of them
+            let queue = SendQueue::new();
+            let unacked = Mutex::new(UnackedDeliveries::default());
+
+            let upstream_listener = async {
+                // hidden_channels is maintained incrementally, so periodically rebuild it from
+                // scratch in case an event was missed
+                let mut resync = tokio::time::interval_at(
+                    Instant::now() + *HIDDEN_CHANNELS_RESYNC,
+                    *HIDDEN_CHANNELS_RESYNC,
+                );
+                // whether events are currently being dropped because the client can't keep up
+                let mut overflowing = false;
+                let mut bandwidth = ByteBucket::from_env();
+
+                loop {
+                    // deliveries that weren't sent to the client are acked along with the next one
+                    // that is, or here once nothing else is waiting
+                    if amqp_rx.is_empty() && queue.is_empty() {
+                        if let Err(e) = unacked
+                            .lock()
+                            .await
+                            .ack(&amqp, session.get_session_id_str())
+                            .await
                         {
-                            bail_with_ctx!(e, "subscribe to dm channels: subscribe");
+                            error!("failed to ack deliveries: {e:?}");
+                            break;
                         }
                     }
-                }
-                Err(e) => {
-                    bail_with_ctx!(e, "fetch dm channels: fetch_all_dm_channels_for_user");
-                }
-            }
-
-            if let Err(e) = amqp
-                .queue_bind(QueueBindArguments {
-                    queue: session.get_session_id_str().to_string(),
-                    exchange: "events".to_string(),
-                    routing_key: format!("{}", session.user_id),
-                    ..Default::default()
-                })
-                .await
-            {
-                bail_with_ctx!(e, "bind queue: queue_bind");
-            }
 
-            let (_, mut amqp_rx) = match amqp
-                .basic_consume_rx(
-                    BasicConsumeArguments::new(
-                        session.get_session_id_str(),
-                        &format!(
-                            "consumer-{}-{}-{}",
-                            session.user_id,
-                            session.get_session_id_str(),
-                            ip
-                        ),
-                    )
-                    .finish(),
-                )
-                .await
-            {
-                Ok(r) => r,
-                Err(e) => {
-                    bail_with_ctx!(e, "channel consume: basic_consume_rx");
-                }
-            };
+                    let message = tokio::select! {
+                        message = amqp_rx.recv() => message,
+                        _ = resync.tick() => {
+                            match compute_hidden_channels(session.user_id).await {
+                                Ok(computed) => (hidden_channels, guild_owners) = computed,
+                                Err(e) => warn!("failed to resync hidden_channels: {e}"),
+                            }
+                            continue;
+                        }
+                    };
+                    let Some(message) = message else {
+                        // the consumer only goes away on its own once the connection to the
+                        // broker is lost, along with every event published in the meantime. the
+                        // client gets them back by reconnecting and starting from a fresh Ready
+                        warn!(
+                            "consumer of session {} ended, closing it",
+                            session.get_session_id_str()
+                        );
+                        let _ = tx
+                            .lock()
+                            .await
+                            .send(Message::Close(Some(
+                                GatewayCloseCode::InternalError
+                                    .close_frame("lost connection to the event broker"),
+                            )))
+                            .await;
+                        break;
+                    };
+                    let ConsumerMessage {
+                        deliver: Some(deliver),
+                        basic_properties,
+                        content: Some(content),
+                        ..
+                    } = message
+                    else {
+                        break;
+                    };
+                    let delivery_tag = deliver.delivery_tag();
 
-            let guilds = match get_pool()
-                .fetch_all_guilds_for_user(
-                    session.user_id,
-                    GetGuildQuery {
-                        channels: true,
-                        roles: true,
-                        ..Default::default()
-                    },
-                )
-                .await
-            {
-                Ok(guilds) => guilds,
-                Err(e) => bail_with_ctx!(e, "create hidden_channels: fetch_all_guilds_for_user"),
-            };
+                    // guild events are published to an exchange named after the guild
+                    if let Ok(guild_id) = deliver.exchange().parse::<u64>() {
+                        if guild_owners.contains_key(&guild_id) {
+                            guild_load::record(guild_id);
+                        }
+                    }
 
-            let mut hidden_channels = {
-                let mut hidden = HashSet::new();
+                    let internal = basic_properties
+                        .as_ref()
+                        .and_then(|properties| properties.message_type())
+                        .and_then(|message_type| InternalEvent::from_message_type(message_type));
+                    if let Some(internal) = internal {
+                        if let Err(e) = amqp
+                            .basic_ack(BasicAckArguments::new(delivery_tag, false))
+                            .await
+                        {
+                            error!("failed to settle delivery: {e:?}");
+                            break;
+                        }
 
-                for guild in guilds {
-                    if guild.partial.owner_id == session.user_id {
+                        match internal {
+                            InternalEvent::TokenRevoked => {
+                                info!(
+                                    "token of user {} was revoked, closing session {}",
+                                    session.user_id,
+                                    session.get_session_id_str()
+                                );
+                                SHUTDOWN_NOTIFIER
+                                    .shutdown(&session.session_id, ShutdownReason::TokenRevoked);
+                            }
+                            // the flag itself was already changed by whoever published this
+                            InternalEvent::PresenceMuted | InternalEvent::PresenceUnmuted => {
+                                if let Err(e) = announce_presence_muted(session.user_id).await {
+                                    error!(
+                                        "failed to announce presence mute of user {}: {e:?}",
+                                        session.user_id
+                                    );
+                                }
+                            }
+                        }
                         continue;
                     }
-                    let base_permissions = get_pool().fetch_member_by_id(guild.partial.id, session.user_id)
-                        .await?
-                        .ok_or("member not found while creating hidden_channels")?
-                        .permissions;
 
-                    if let Some(channels) = guild.channels {
-                        if channels.is_empty() {
-                            continue;
+                    let decoded =
+                        bincode::decode_from_slice::<OutboundMessage, _>(&content, CONFIG);
+                    // decoded events are only acked once they were sent to the client, or skipped
+                    let settled = match decoded {
+                        Ok(_) => {
+                            unacked.lock().await.push(delivery_tag);
+                            Ok(())
                         }
-
-                        let mut roles = guild.roles.unwrap_or_default();
-                        roles.sort_unstable_by_key(|r| r.position);
-
-                        for channel in channels {
-                            let perm = calculate_permissions_sorted(
-                                session.user_id,
-                                base_permissions,
-                                &roles,
-                                Some(&channel.overwrites),
+                        Err(ref e) => {
+                            warn!(
+                                "dead-lettering message for session {} that can't be decoded: {e}",
+                                session.get_session_id_str()
                             );
+                            counter!("harmony_dead_lettered_total").increment(1);
+                            let dead_lettered = dead_letter(
+                                &amqp,
+                                &deliver,
+                                content.clone(),
+                                session.get_session_id_str(),
+                                session.user_id,
+                                &e.to_string(),
+                            )
+                            .await;
 
-                            if !perm.contains(Permissions::VIEW_CHANNEL) {
-                                hidden.insert(channel.id);
+                            match dead_lettered {
+                                Ok(()) => {
+                                    amqp
+                                        .basic_ack(BasicAckArguments::new(delivery_tag, false))
+                                        .await
+                                }
+                                Err(e) => {
+                                    // the broker still dead-letters it, just without the context
+                                    warn!("failed to dead-letter message: {e:?}");
+                                    amqp
+                                        .basic_nack(BasicNackArguments::new(
+                                            delivery_tag,
+                                            false,
+                                            false,
+                                        ))
+                                        .await
+                                }
                             }
                         }
+                    };
+                    if let Err(e) = settled {
+                        error!("failed to settle delivery: {e:?}");
+                        break;
                     }
-                }
 
-                hidden
-            };
+                    if let Ok((mut event, _)) = decoded {
+                        if let OutboundMessage::PresenceUpdate { presence } = &mut event {
+                            if presence.user_id != session.user_id
+                                && !session.settings.intents.contains(Intents::PRESENCES)
+                            {
+                                continue;
+                            }
+                            session.settings.strip_presence(presence);
+                        }
 
-            let upstream_listener = async {
-                while let Some(ConsumerMessage {
-                    content: Some(content),
-                    ..
-                }) = amqp_rx.recv().await
-                {
-                    if let Ok((event, _)) =
-                        bincode::decode_from_slice::<OutboundMessage, _>(&content, CONFIG)
-                    {
                         match &event {
+                            // owners can view every channel of their guilds
+                            OutboundMessage::ChannelCreate {
+                                channel: EssenceChannel::Guild(chan),
+                                ..
+                            }
+                            | OutboundMessage::ChannelUpdate {
+                                after: EssenceChannel::Guild(chan),
+                                ..
+                            } if guild_owners.get(&chan.guild_id) == Some(&session.user_id) => {}
+                            OutboundMessage::RoleCreate { role }
+                            | OutboundMessage::RoleUpdate { after: role, .. }
+                                if guild_owners.get(&role.guild_id) == Some(&session.user_id) => {}
                             OutboundMessage::ChannelCreate {
                                 channel: EssenceChannel::Dm(chan),
                                 ..
                             } => {
-                                if let Err(e) =
-                                    subscribe(&amqp, chan.id, session.get_session_id_str(), "topic")
-                                        .await
+                                if let Err(e) = subscribe(
+                                    &amqp,
+                                    chan.id,
+                                    session.queue_name(),
+                                    "topic",
+                                    session.settings.intents,
+                                )
+                                .await
                                 {
                                     error!("failed to subscribe to amqp exchange: {e:?}");
                                     break;
@@ -420,6 +929,7 @@ pub async fn process_events(
                                     .await
                                 {
                                     Ok(Some(guild)) => {
+                                        guild_owners.insert(guild.partial.id, guild.partial.owner_id);
                                         if guild.partial.owner_id != session.user_id {
                                             if let Err(e) = update_hidden_channels(
                                                 guild.partial.id,
@@ -459,6 +969,7 @@ pub async fn process_events(
                                     .await
                                 {
                                     Ok(Some(guild)) => {
+                                        guild_owners.insert(guild.partial.id, guild.partial.owner_id);
                                         if guild.partial.owner_id != session.user_id {
                                             if let Err(e) = update_hidden_channels(
                                                 guild.partial.id,
@@ -483,21 +994,34 @@ pub async fn process_events(
                                     }
                                 }
                             }
+                            OutboundMessage::ChannelUpdate {
+                                after: EssenceChannel::Dm(_),
+                                ..
+                            } => {
+                                // DM channels are never hidden, so the update (which carries both
+                                // `before` and `after`) is forwarded as is
+                            }
                             OutboundMessage::ChannelDelete { channel_id, .. } => {
-                                if let Err(e) =
-                                    unsubscribe(&amqp, channel_id, session.get_session_id_str())
-                                        .await
+                                if let Err(e) = unsubscribe(
+                                    &amqp,
+                                    channel_id,
+                                    session.queue_name(),
+                                    session.settings.intents,
+                                )
+                                .await
                                 {
                                     error!("failed to unsubscribe to amqp exchange: {e:?}");
                                     break;
                                 }
                             }
                             OutboundMessage::GuildCreate { guild, .. } => {
+                                guild_owners.insert(guild.partial.id, guild.partial.owner_id);
                                 if let Err(e) = subscribe(
                                     &amqp,
                                     guild.partial.id,
-                                    session.get_session_id_str(),
+                                    session.queue_name(),
                                     "topic",
+                                    session.settings.intents,
                                 )
                                 .await
                                 {
@@ -505,17 +1029,61 @@ pub async fn process_events(
                                     break;
                                 }
                             }
-                            OutboundMessage::GuildRemove { guild_id, .. } => {
-                                if let Err(e) =
-                                    unsubscribe(&amqp, guild_id, session.get_session_id_str()).await
+                            OutboundMessage::GuildUpdate { after, .. } => {
+                                let owner_id = after.partial.owner_id;
+                                let previous = guild_owners.insert(after.partial.id, owner_id);
+
+                                // an owner sees every channel, so the user's view of the guild
+                                // changes when they gain or lose ownership of it
+                                if previous.is_some_and(|previous| previous != owner_id)
+                                    && (previous == Some(session.user_id)
+                                        || owner_id == session.user_id)
                                 {
-                                    error!("failed to unsubscribe to amqp exchange: {e:?}");
-                                    break;
+                                    match compute_hidden_channels(session.user_id).await {
+                                        Ok(computed) => (hidden_channels, guild_owners) = computed,
+                                        Err(e) => warn!("failed to resync hidden_channels: {e}"),
+                                    }
+                                }
+                            }
+                            // a kick or ban may arrive along with GuildRemove, whichever comes first
+                            // unsubscribes from the guild and forgets its hidden channels
+                            OutboundMessage::GuildRemove { guild_id, .. } => {
+                                if guild_owners.remove(guild_id).is_some() {
+                                    hidden_channels.retain(|_, hidden_in| *hidden_in != *guild_id);
+                                    if let Err(e) = unsubscribe(
+                                        &amqp,
+                                        guild_id,
+                                        session.queue_name(),
+                                        session.settings.intents,
+                                    )
+                                    .await
+                                    {
+                                        error!("failed to unsubscribe to amqp exchange: {e:?}");
+                                        break;
+                                    }
+                                }
+                            }
+                            OutboundMessage::MemberRemove { guild_id, user_id, .. }
+                                if *user_id == session.user_id =>
+                            {
+                                if guild_owners.remove(guild_id).is_some() {
+                                    hidden_channels.retain(|_, hidden_in| *hidden_in != *guild_id);
+                                    if let Err(e) = unsubscribe(
+                                        &amqp,
+                                        guild_id,
+                                        session.queue_name(),
+                                        session.settings.intents,
+                                    )
+                                    .await
+                                    {
+                                        error!("failed to unsubscribe to amqp exchange: {e:?}");
+                                        break;
+                                    }
                                 }
                             }
                             OutboundMessage::MessageCreate { message, .. }
                             | OutboundMessage::MessageUpdate { after: message, .. } => {
-                                if hidden_channels.contains(&message.channel_id) {
+                                if hidden_channels.contains_key(&message.channel_id) {
                                     continue;
                                 }
                             }
@@ -533,6 +1101,7 @@ pub async fn process_events(
                                     .await
                                 {
                                     Ok(Some(guild)) => {
+                                        guild_owners.insert(guild.partial.id, guild.partial.owner_id);
                                         if guild.partial.owner_id != session.user_id {
                                             if let Some(channels) = guild.channels {
                                                 if channels.is_empty() {
@@ -557,7 +1126,7 @@ pub async fn process_events(
                                                     );
 
                                                     if !perm.contains(Permissions::VIEW_CHANNEL) {
-                                                        hidden_channels.insert(channel.id);
+                                                        hidden_channels.insert(channel.id, guild.partial.id);
                                                         continue;
                                                     }
                                                 }
@@ -576,17 +1145,151 @@ pub async fn process_events(
                             }
                             _ => {}
                         }
-                        if let Err(e) = tx.lock().await.send(session.encode(&event)).await {
-                            debug!("failed to send to client: {e:?}");
-                            break;
+
+                        let message = session.encode(&event);
+                        let len = message.len();
+                        if let Some(bandwidth) = &mut bandwidth {
+                            if is_low_priority(&event) {
+                                if !bandwidth.try_take(len) {
+                                    counter!("harmony_shed_events_total").increment(1);
+                                    continue;
+                                }
+                            } else {
+                                let wait = bandwidth.take(len);
+                                if !wait.is_zero() {
+                                    counter!("harmony_throttled_events_total").increment(1);
+                                    tokio::time::sleep(wait).await;
+                                }
+                            }
+                        }
+
+                        let outgoing = Outgoing {
+                            message,
+                            delivery_tag,
+                        };
+                        if !queue.push(outgoing).await {
+                            overflowing = false;
+                            continue;
+                        }
+                        if !overflowing {
+                            warn!(
+                                "session {} of user {} can't keep up with its events, dropping the {}",
+                                session.get_session_id_str(),
+                                session.user_id,
+                                SEND_QUEUE_OVERFLOW.dropped(),
+                            );
+                            overflowing = true;
                         }
+                        counter!("harmony_dropped_messages_total").increment(1);
+                    }
+                }
+            };
+
+            let writer = async {
+                loop {
+                    let Outgoing {
+                        message,
+                        delivery_tag,
+                    } = queue.pop().await;
+                    let len = message.len();
+
+                    if let Err(e) = tx.lock().await.send(message).await {
+                        debug!("failed to send to client: {e:?}");
+                        break;
+                    }
+                    if let Err(e) = unacked
+                        .lock()
+                        .await
+                        .ack_through(&amqp, session.get_session_id_str(), delivery_tag)
+                        .await
+                    {
+                        error!("failed to ack deliveries: {e:?}");
+                        break;
                     }
+                    counter!("harmony_events_dispatched_total").increment(1);
+                    counter!("harmony_outbound_bytes_total").increment(len as u64);
                 }
             };
 
             let ws_listener = async {
-                while let Ok(Some(mut msg)) = rx.try_next().await {
+                let mut current_presence = identified_presence.clone();
+                let mut last_activity = Instant::now();
+                // server pings that the client hasn't answered with a pong yet
+                let mut pending_pongs = 0_usize;
+                let mut heartbeat = tokio::time::interval(*HEARTBEAT_INTERVAL);
+                heartbeat.tick().await;
+
+                loop {
+                    let next = tokio::select! {
+                        next = rx.try_next() => next,
+                        _ = heartbeat.tick() => {
+                            let mut tx = tx.lock().await;
+
+                            if pending_pongs >= MAX_PENDING_PONGS {
+                                warn!(
+                                    "session {} did not acknowledge {MAX_PENDING_PONGS} pings, closing",
+                                    session.get_session_id_str()
+                                );
+                                let _ = tx
+                                    .send(Message::Close(Some(CloseFrame {
+                                        code: CloseCode::Policy,
+                                        reason: "pong not acknowledged".into(),
+                                    })))
+                                    .await;
+                                break;
+                            }
+                            pending_pongs += 1;
+
+                            if let Err(e) = tx.send(Message::Ping(Vec::new())).await {
+                                warn!("failed to send: {e:?}");
+                                break;
+                            }
+                            continue;
+                        }
+                        _ = tokio::time::sleep_until(last_activity + *IDLE_AFTER),
+                            if !idle.load(Ordering::Relaxed)
+                                && current_presence.0 == PresenceStatus::Online =>
+                        {
+                            idle.store(true, Ordering::Relaxed);
+
+                            if let Err(e) =
+                                apply_idle(session.user_id, &presence_session, PresenceStatus::Idle).await
+                            {
+                                error!("failed to mark session as idle: {e:?}");
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    let Ok(Some(mut msg)) = next else {
+                        break;
+                    };
+                    // pongs are answered by the client library itself, so they don't count as
+                    // user activity
+                    if let Message::Pong(_) = msg {
+                        pending_pongs = 0;
+                        continue;
+                    }
+
                     if let Ok(incoming) = session.decode::<InboundMessage>(&mut msg) {
+                        // heartbeats are sent by the client on its own, so they don't count as
+                        // user activity either
+                        if !matches!(incoming, InboundMessage::Ping) {
+                            last_activity = Instant::now();
+
+                            // an explicit presence update supersedes the one being restored
+                            if idle.swap(false, Ordering::Relaxed)
+                                && !matches!(incoming, InboundMessage::UpdatePresence { .. })
+                            {
+                                if let Err(e) =
+                                    apply_idle(session.user_id, &presence_session, current_presence.0).await
+                                {
+                                    error!("failed to restore presence after idle: {e:?}");
+                                    break;
+                                }
+                            }
+                        }
+
                         match incoming {
                             InboundMessage::Ping => {
                                 if let Err(e) = tx
@@ -603,41 +1306,56 @@ pub async fn process_events(
                                 status,
                                 custom_status
                             } => {
-                                if let Some(status) = &custom_status {
-                                    if status.len() > 256 {
+                                let custom_status = match normalize_custom_status(custom_status) {
+                                    Ok(custom_status) => custom_status,
+                                    Err(e) => {
                                         if let Err(e) = tx
                                             .lock()
                                             .await
-                                            .send(Message::Close(
-                                                Some(CloseFrame {
-                                                    code: CloseCode::Policy,
-                                                    reason: "status length exceeds 256 characters".into() 
-                                            }))).await {
-                                                warn!("failed to send: {e:?}");
+                                            .send(Message::Close(Some(
+                                                GatewayCloseCode::InvalidPayload.close_frame(e.to_string()),
+                                            )))
+                                            .await
+                                        {
+                                            warn!("failed to send: {e:?}");
                                         }
                                         break;
                                     }
-                                }
+                                };
 
-                                if let Err(e) = update_presence(session.user_id, status, custom_status.clone()).await {
+                                let aggregate = match set_session_status(session.user_id, &presence_session, status).await {
+                                    Ok(aggregate) => aggregate,
+                                    Err(e) => {
+                                        error!("failed to update session status, redis error: {e:?}");
+                                        let _ = tx
+                                            .lock()
+                                            .await
+                                            .send(Message::Close(Some(
+                                                GatewayCloseCode::InternalError.close_frame(format!("redis error: {e:?}")),
+                                            )))
+                                            .await;
+                                        break;
+                                    }
+                                };
+
+                                if let Err(e) = update_presence(session.user_id, aggregate, status, custom_status.clone()).await {
                                     error!("failed to update presence, redis error: {e:?}");
                                     let _ = tx
                                         .lock()
                                         .await
-                                        .send(Message::Close(Some(CloseFrame {
-                                            code: CloseCode::Error,
-                                            reason: format!("redis error: {e:?}").into(),
-                                        })))
+                                        .send(Message::Close(Some(
+                                            GatewayCloseCode::InternalError.close_frame(format!("redis error: {e:?}")),
+                                        )))
                                         .await;
                                     break;
                                 }
+                                current_presence = (status, custom_status.clone());
 
                                 if let Err(e) = publish_presence_change(
-                                    &amqp,
                                     session.user_id,
                                     Presence {
                                         user_id: session.user_id,
-                                        status,
+                                        status: aggregate,
                                         custom_status,
                                         devices: match get_devices(session.user_id).await {
                                             Ok(devices) => devices,
@@ -672,33 +1390,93 @@ pub async fn process_events(
                 _ = upstream_listener => {
                     debug!("upstream died");
                 },
+                _ = writer => {
+                    debug!("writer died");
+                },
                 _ = ws_listener => {
                     debug!("ws_listener died")
                 }
+                reason = shutdown_rx => {
+                    debug!("session {} was shut down: {reason:?}", session.get_session_id_str());
+
+                    let frame = match reason {
+                        Ok(ShutdownReason::Restart) => GatewayCloseCode::ServerRestarting
+                            .close_frame("server restarting, please reconnect"),
+                        Ok(ShutdownReason::TokenRevoked) => {
+                            GatewayCloseCode::AuthenticationFailed.close_frame("token revoked")
+                        }
+                        _ => GatewayCloseCode::SessionTerminated.close_frame("session terminated"),
+                    };
+
+                    // the other listeners may still be holding the lock
+                    if let Ok(ref mut tx) = tx.try_lock() {
+                        let _ = tx.send(Message::Close(Some(frame))).await;
+                    }
+                }
             }
 
             Ok(())
         }
         .await;
 
+        gauge!("harmony_active_sessions").decrement(1.0);
         let cleanup: Result<()> = {
-            remove_session(session.user_id, session.get_session_id_str()).await?;
+            let _ = amqp.close().await;
+
+            SHUTDOWN_NOTIFIER.remove(&session.session_id);
+            TASK_MANAGER.shutdown(&session.session_id);
+            remove_session(session.user_id, &presence_session).await?;
             if !any_session_exists(session.user_id).await? {
-                publish_presence_change(
-                    &amqp,
-                    session.user_id,
-                    Presence {
-                        user_id: session.user_id,
-                        status: PresenceStatus::Offline,
-                        custom_status: None,
-                        devices: Devices::empty(),
-                        online_since: None,
-                    },
-                )
-                .await?;
-                update_presence(session.user_id, PresenceStatus::Offline, None).await?;
+                let user_id = session.user_id;
+                let token = session.get_session_id_str().to_string();
+                schedule_offline(user_id, &token).await?;
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(*OFFLINE_GRACE).await;
+
+                    let result: Result<()> = async {
+                        if take_pending_offline(user_id, &token).await?
+                            && !any_session_exists(user_id).await?
+                        {
+                            publish_presence_change(
+                                user_id,
+                                Presence {
+                                    user_id,
+                                    status: PresenceStatus::Offline,
+                                    custom_status: None,
+                                    devices: Devices::empty(),
+                                    online_since: None,
+                                },
+                            )
+                            .await?;
+                            update_presence(
+                                user_id,
+                                PresenceStatus::Offline,
+                                PresenceStatus::Offline,
+                                None,
+                            )
+                            .await?;
+                            record_last_seen(user_id).await?;
+                        }
+
+                        Ok(())
+                    }
+                    .await;
+                    if let Err(e) = result {
+                        error!(
+                            "failed to publish delayed offline presence for user {user_id}: {e:?}"
+                        );
+                    }
+                });
+            } else {
+                // the remaining sessions keep the user online, but observers still need to see
+                // the reduced device set and possibly a later `online_since`
+                recompute_status(session.user_id).await?;
+
+                if let Some(presence) = get_presences_bulk(&[session.user_id]).await?.pop() {
+                    publish_presence_change(session.user_id, presence).await?;
+                }
             }
-            amqp.close().await?;
 
             Ok(())
         };
@@ -707,10 +1485,9 @@ pub async fn process_events(
         if let Err(e) = inner {
             if let Ok(ref mut tx) = tx.try_lock() {
                 let _ = tx
-                    .send(Message::Close(Some(CloseFrame {
-                        code: CloseCode::Abnormal,
-                        reason: e.to_string().into(),
-                    })))
+                    .send(Message::Close(Some(
+                        GatewayCloseCode::InternalError.close_frame(e.to_string()),
+                    )))
                     .await;
             }
             error!(
@@ -733,15 +1510,42 @@ pub async fn process_events(
             );
         }
     } else {
+        counter!("harmony_identify_failures_total", "reason" => "not_identify").increment(1);
         let _ = tx
             .lock()
             .await
-            .send(Message::Close(Some(CloseFrame {
-                code: CloseCode::Policy,
-                reason: format!("expected `identify` event").into(),
-            })))
+            .send(Message::Close(Some(
+                GatewayCloseCode::InvalidPayload.close_frame(format!("expected `identify` event")),
+            )))
             .await;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_VERSION;
+
+    #[test]
+    fn gateway_info_reports_versions() {
+        let GatewayNotice::GatewayInfo {
+            version,
+            protocol_versions,
+            ..
+        } = gateway_info();
+
+        assert_eq!(version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(protocol_versions, SUPPORTED_VERSIONS);
+        assert!(protocol_versions.contains(&DEFAULT_VERSION));
+    }
+
+    #[test]
+    fn gateway_info_is_tagged() {
+        let encoded = simd_json::to_string(&gateway_info()).unwrap();
+
+        assert!(encoded.contains(r#""event":"gateway_info""#));
+        assert!(encoded.contains(&format!(r#""version":"{}""#, env!("CARGO_PKG_VERSION"))));
+    }
+}